@@ -1,8 +1,10 @@
 use clap::*;
+use macros::plan::Plan;
 use serde::*;
 use std::fmt::Write as _;
 use std::fs;
 use std::io::prelude::*;
+use std::io::Cursor;
 
 const METADATA_FILE: &str = "info.yaml";
 
@@ -81,6 +83,8 @@ impl Args {
             .filter_map(Result::ok)
             .filter(|v| v.path().extension().filter(|&v| v == "cbz").is_some());
 
+        let mut plan = Plan::new();
+
         for cbz in cbz_files {
             log::info!("{:?}", cbz.path());
 
@@ -97,14 +101,10 @@ impl Args {
             // let new_path = PathBuf::from(&self.location).join(&yaml.title);
             let filename = path.file_stem().unwrap();
             let new_path = path.parent().unwrap().join(filename);
-            let new_cbz = new_path.join(&path.file_name().unwrap());
-
-            fs::create_dir(&new_path).ok();
-            fs::rename(&path, new_cbz)?;
+            let new_cbz = new_path.join(path.file_name().unwrap());
 
-            let info_file = fs::File::create(new_path.join("details.json"))?;
             let mihon_metadata = MihonMetadata::from_cbz_metadata(&yaml);
-            serde_json::to_writer(info_file, &mihon_metadata)?;
+            let details = serde_json::to_vec(&mihon_metadata)?;
 
             // thumbnail
             let mut files = zip
@@ -115,20 +115,26 @@ impl Args {
             files.sort();
 
             let thumbnail_name = &files[yaml.thumbnail as usize - 1];
-            let mut thumbnail = zip.by_name(&thumbnail_name)?;
-            let mut thumbnail_file = fs::File::create(new_path.join("cover.jpg"))?;
+            let mut thumbnail = zip.by_name(thumbnail_name)?;
 
             let mut buf = Vec::new();
             thumbnail.read_to_end(&mut buf)?;
 
-            if thumbnail_name.ends_with(".jpg") {
-                thumbnail_file.write_all(&buf)?;
+            let cover = if thumbnail_name.ends_with(".jpg") {
+                buf
             } else {
                 let image = image::load_from_memory(&buf)?;
-                image.write_to(&mut thumbnail_file, image::ImageFormat::Jpeg)?;
-            }
+                let mut cover = Cursor::new(Vec::new());
+                image.write_to(&mut cover, image::ImageFormat::Jpeg)?;
+                cover.into_inner()
+            };
+
+            plan.mkdir(&new_path);
+            plan.rename(&path, new_cbz);
+            plan.write(new_path.join("details.json"), details);
+            plan.write(new_path.join("cover.jpg"), cover);
         }
 
-        Ok(())
+        plan.execute_reported()
     }
 }
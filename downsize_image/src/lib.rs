@@ -1,9 +1,13 @@
 use clap::Parser;
-use image::{self, imageops, ImageFormat};
+use image::{self, ImageFormat, imageops};
+use macros::cancel::{self, Cancelled, Partial};
+use macros::report::Item;
 use rayon::prelude::*;
 use std::fs::{self, File};
 use std::io::{self, BufReader, BufWriter, Cursor, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use zip::{read::ZipArchive, write::FullFileOptions};
 
 #[derive(Parser)]
@@ -20,29 +24,66 @@ pub struct Args {
 
 impl Args {
     pub fn exec(&self) -> anyhow::Result<()> {
-        let args = Args::parse();
-        let entries = fs::read_dir(&args.path)?;
+        let entries = fs::read_dir(&self.path)?;
 
-        let out_path = args.path.join("out");
-        fs::create_dir_all(&out_path).ok();
+        let out_path = self.path.join("out");
+
+        if macros::global().dry_run {
+            log::info!("mkdir {:?}", out_path);
+        } else {
+            fs::create_dir_all(&out_path).ok();
+        }
+
+        let finished = Mutex::new(Vec::new());
+        let total = AtomicUsize::new(0);
 
         entries.par_bridge().try_for_each(|entry| {
             let entry = entry?;
             let path = entry.path();
+
+            if cancel::is_cancelled()
+                || !path.is_file()
+                || path.extension().and_then(|s| s.to_str()) != Some("zip")
+            {
+                return anyhow::Ok(());
+            }
+
+            total.fetch_add(1, Ordering::Relaxed);
             log::info!("Processing file: {}\n", path.display());
-            if path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("zip") {
-                if let Err(why) = process_zip(&path, args.min_size) {
+
+            let item = Item::new("downsize", &path);
+
+            match process_zip(&path, self.min_size) {
+                Ok(Some(output)) => {
+                    item.output(output).succeeded();
+                    finished.lock().unwrap().push(path);
+                }
+                Ok(None) => {
+                    item.skipped("Not smaller than the original");
+                    finished.lock().unwrap().push(path);
+                }
+                Err(_) if cancel::is_cancelled() => item.failed(Cancelled),
+                Err(why) => {
                     eprintln!("Failed to process {}: {}", path.display(), why);
+                    item.failed(why);
                 }
             }
+
             anyhow::Ok(())
         })?;
 
+        if cancel::is_cancelled() {
+            let finished = finished.into_inner().unwrap();
+            cancel::summary(&finished, total.into_inner());
+            return Err(Cancelled.into());
+        }
+
         Ok(())
     }
 }
 
-fn process_zip(zip_path: &Path, min_size: u32) -> io::Result<()> {
+/// Write the downsized copy into `out`, returns its path or `None` when it is not any smaller
+fn process_zip(zip_path: &Path, min_size: u32) -> io::Result<Option<PathBuf>> {
     let file = File::open(zip_path)?;
     let reader = BufReader::new(file);
     let mut archive = ZipArchive::new(reader)?;
@@ -53,11 +94,19 @@ fn process_zip(zip_path: &Path, min_size: u32) -> io::Result<()> {
         .join("out")
         .join(zip_path.file_name().unwrap());
 
-    let new_file = File::create(&new_zip_path)?;
+    if macros::global().dry_run {
+        log::info!("downsize {:?} => {:?}", zip_path, new_zip_path);
+        return Ok(Some(new_zip_path));
+    }
+
+    let partial = Partial::replacing(new_zip_path);
+    let new_file = File::create(partial.path())?;
     let writer_buf = BufWriter::new(new_file);
     let mut writer = zip::ZipWriter::new(writer_buf);
 
     for i in 0..archive.len() {
+        cancel::check().map_err(|why| io::Error::new(io::ErrorKind::Interrupted, why))?;
+
         let mut zip_file = archive.by_index(i)?;
         if zip_file.is_dir() {
             continue;
@@ -94,18 +143,18 @@ fn process_zip(zip_path: &Path, min_size: u32) -> io::Result<()> {
 
                 let mut resized_data = Vec::new();
                 let mut cursor = Cursor::new(&mut resized_data);
-                if resized_img.write_to(&mut cursor, ImageFormat::Jpeg).is_ok() {
-                    if resized_data.len() < original_data.len() {
-                        let name = Path::new(&name)
-                            .with_extension("jpg")
-                            .to_string_lossy()
-                            .to_string();
-
-                        writer.start_file(&name, options)?;
-                        writer.write_all(&resized_data)?;
-                        writer.flush()?;
-                        continue;
-                    }
+                if resized_img.write_to(&mut cursor, ImageFormat::Jpeg).is_ok()
+                    && resized_data.len() < original_data.len()
+                {
+                    let name = Path::new(&name)
+                        .with_extension("jpg")
+                        .to_string_lossy()
+                        .to_string();
+
+                    writer.start_file(&name, options)?;
+                    writer.write_all(&resized_data)?;
+                    writer.flush()?;
+                    continue;
                 }
             }
 
@@ -123,15 +172,15 @@ fn process_zip(zip_path: &Path, min_size: u32) -> io::Result<()> {
     // Ensure the writer is dropped to flush and close the file
     // drop(writer);
 
-    // Compare sizes
+    // Compare sizes, the partial output is dropped when it is not any smaller
     let original_size = fs::metadata(zip_path)?.len();
-    let new_size = fs::metadata(&new_zip_path)?.len();
+    let new_size = fs::metadata(partial.path())?.len();
 
     if new_size >= original_size {
-        fs::remove_file(&new_zip_path)?;
+        return Ok(None);
     }
 
-    Ok(())
+    Ok(Some(partial.finish()?))
 }
 
 fn is_likely_image(name: &str) -> bool {
@@ -0,0 +1,73 @@
+use std::fmt;
+use std::io;
+use std::process::ExitStatus;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The executable could not be started, most likely it is not installed
+    Spawn {
+        program: &'static str,
+        source: io::Error,
+    },
+    /// The process exited with a failure, `stderr` holds the end of what it printed
+    Failed {
+        command: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+    /// ffprobe printed something that is not the JSON we asked for
+    Parse {
+        path: String,
+        source: serde_json::Error,
+    },
+    /// Killed because the caller asked for it through `cancel_when`
+    Cancelled,
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Spawn { program, source } => write!(f, "Cannot execute {program}: {source}"),
+            Self::Failed {
+                command,
+                status,
+                stderr,
+            } => {
+                write!(f, "{command} exited with {status}")?;
+
+                let stderr = stderr.trim();
+
+                if !stderr.is_empty() {
+                    write!(f, "\n{stderr}")?;
+                }
+
+                Ok(())
+            }
+            Self::Parse { path, source } => {
+                write!(f, "Cannot parse the ffprobe output of {path}: {source}")
+            }
+            Self::Cancelled => f.write_str("Cancelled"),
+            Self::Io(why) => write!(f, "{why}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Spawn { source, .. } => Some(source),
+            Self::Parse { source, .. } => Some(source),
+            Self::Io(why) => Some(why),
+            Self::Failed { .. } | Self::Cancelled => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(why: io::Error) -> Self {
+        Self::Io(why)
+    }
+}
@@ -0,0 +1,302 @@
+//! Thin layer over the `ffmpeg` and `ffprobe` executables
+
+mod error;
+pub mod probe;
+pub mod progress;
+
+pub use error::{Error, Result};
+pub use probe::{probe, Probe};
+pub use progress::Progress;
+
+use progress::ProgressParser;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, BufRead as _, BufReader, Read as _, Write as _};
+use std::path::Path;
+use std::process::{Child, ChildStderr, Command, Stdio};
+use std::thread::{self, JoinHandle};
+
+/// How much of the end of stderr is kept for the error message
+const STDERR_TAIL: usize = 16 * 1024;
+
+/// Names of the encoders this ffmpeg was built with, from `ffmpeg -encoders`
+pub fn encoders() -> Result<Vec<String>> {
+    let mut command = Command::new("ffmpeg");
+    command
+        .args(["-hide_banner", "-encoders"])
+        .stdin(Stdio::null());
+
+    let output = command.output().map_err(|source| Error::Spawn {
+        program: "ffmpeg",
+        source,
+    })?;
+
+    if !output.status.success() {
+        return Err(Error::Failed {
+            command: format!("{:?}", command),
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        });
+    }
+
+    Ok(parse_encoders(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// A legend first, then ` ------` and a line per encoder: ` V....D libx264   description`
+fn parse_encoders(list: &str) -> Vec<String> {
+    list.lines()
+        .skip_while(|v| !v.trim_start().starts_with("---"))
+        .skip(1)
+        .filter_map(|v| v.split_whitespace().nth(1))
+        .map(String::from)
+        .collect()
+}
+
+/// Builder for an ffmpeg invocation.
+///
+/// Arguments are kept in the order they are given, so global and input options have to be
+/// added before their `input`, output options before their `output`.
+#[derive(Debug, Clone)]
+pub struct Ffmpeg {
+    args: Vec<OsString>,
+    overwrite: bool,
+    echo: bool,
+    cancel: Option<fn() -> bool>,
+}
+
+impl Default for Ffmpeg {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ffmpeg {
+    pub fn new() -> Self {
+        Self {
+            args: vec!["-hide_banner".into(), "-nostdin".into()],
+            overwrite: false,
+            echo: true,
+            cancel: None,
+        }
+    }
+
+    pub fn arg(mut self, arg: impl AsRef<OsStr>) -> Self {
+        self.args.push(arg.as_ref().to_owned());
+        self
+    }
+
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.args
+            .extend(args.into_iter().map(|v| v.as_ref().to_owned()));
+        self
+    }
+
+    pub fn input(self, path: impl AsRef<Path>) -> Self {
+        self.arg("-i").arg(path.as_ref())
+    }
+
+    pub fn output(self, path: impl AsRef<Path>) -> Self {
+        self.arg(path.as_ref())
+    }
+
+    /// Overwrite existing outputs, by default ffmpeg fails when an output exists
+    pub fn overwrite(mut self, overwrite: bool) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Forward ffmpeg's stderr to ours while it runs, on by default. The end of it is always
+    /// captured for the error message.
+    pub fn echo(mut self, echo: bool) -> Self {
+        self.echo = echo;
+        self
+    }
+
+    /// Kill ffmpeg as soon as `cancel` returns true, it is checked on every progress report
+    pub fn cancel_when(mut self, cancel: fn() -> bool) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// The ffmpeg command, with its `-progress` report going to stdout
+    pub fn command(&self) -> Command {
+        let mut command = Command::new("ffmpeg");
+
+        command
+            .arg(if self.overwrite { "-y" } else { "-n" })
+            .args(["-progress", "pipe:1"])
+            .args(&self.args)
+            .stdin(Stdio::null());
+
+        command
+    }
+
+    /// Run until ffmpeg exits
+    pub fn run(self) -> Result<()> {
+        self.run_with_progress(|_| {})
+    }
+
+    /// Run until ffmpeg exits, reporting its `-progress` output along the way
+    pub fn run_with_progress(self, on_progress: impl FnMut(Progress)) -> Result<()> {
+        self.spawn()?.wait(on_progress)
+    }
+
+    /// Run until ffmpeg exits and give back the end of its log, for the filters that report
+    /// there like `cropdetect` or `ssim`
+    pub fn run_for_log(self) -> Result<String> {
+        self.spawn()?.wait_for_log(|_| {})
+    }
+
+    /// Start ffmpeg without waiting for it
+    pub fn spawn(self) -> Result<Running> {
+        let mut running = Running::spawn(&mut self.command(), self.echo)?;
+        running.cancel = self.cancel;
+        Ok(running)
+    }
+}
+
+/// An ffmpeg process that is still running
+pub struct Running {
+    command: String,
+    child: Child,
+    stderr: JoinHandle<Vec<u8>>,
+    cancel: Option<fn() -> bool>,
+}
+
+impl Running {
+    fn spawn(command: &mut Command, echo: bool) -> Result<Self> {
+        log::debug!("Executing command\n{:?}", command);
+
+        let mut child = command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|source| Error::Spawn {
+                program: "ffmpeg",
+                source,
+            })?;
+
+        let stderr = child.stderr.take().expect("piped stderr");
+
+        Ok(Self {
+            command: format!("{:?}", command),
+            child,
+            stderr: thread::spawn(move || tail_stderr(stderr, echo)),
+            cancel: None,
+        })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    pub fn kill(&mut self) -> io::Result<()> {
+        self.child.kill()
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel.is_some_and(|f| f())
+    }
+
+    pub fn wait(self, on_progress: impl FnMut(Progress)) -> Result<()> {
+        self.wait_for_log(on_progress).map(|_| ())
+    }
+
+    /// Like [`Running::wait`], giving back the end of the log
+    pub fn wait_for_log(mut self, mut on_progress: impl FnMut(Progress)) -> Result<String> {
+        if let Some(stdout) = self.child.stdout.take() {
+            let mut parser = ProgressParser::default();
+
+            for line in BufReader::new(stdout).lines() {
+                if self.is_cancelled() {
+                    self.child.kill().ok();
+                    break;
+                }
+
+                if let Some(progress) = parser.feed(&line?) {
+                    on_progress(progress);
+                }
+            }
+        }
+
+        let status = self.child.wait()?;
+
+        // ffmpeg also gets the Ctrl-C of the terminal, it may have exited on its own
+        if self.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+
+        let stderr = self.stderr.join().unwrap_or_default();
+        let stderr = String::from_utf8_lossy(&stderr).into_owned();
+
+        if !status.success() {
+            return Err(Error::Failed {
+                command: self.command,
+                status,
+                stderr,
+            });
+        }
+
+        Ok(stderr)
+    }
+}
+
+/// Copy stderr through while keeping its last few kilobytes
+fn tail_stderr(mut stderr: ChildStderr, echo: bool) -> Vec<u8> {
+    let mut tail = Vec::new();
+    let mut buf = [0; 4096];
+
+    while let Ok(n @ 1..) = stderr.read(&mut buf) {
+        if echo {
+            io::stderr().write_all(&buf[..n]).ok();
+        }
+
+        tail.extend_from_slice(&buf[..n]);
+
+        if tail.len() > STDERR_TAIL {
+            tail.drain(..tail.len() - STDERR_TAIL);
+        }
+    }
+
+    tail
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoders() {
+        let list = "\
+Encoders:
+ V..... = Video
+ A..... = Audio
+ S..... = Subtitle
+ .F.... = Frame-level multithreading
+ ..S... = Slice-level multithreading
+ ...X.. = Codec is experimental
+ ....B. = Supports draw_horiz_band
+ .....D = Supports direct rendering method 1
+ ------
+ V....D libx264              libx264 H.264 / AVC / MPEG-4 AVC / MPEG-4 part 10 (codec h264)
+ V....D h264_nvenc           NVIDIA NVENC H.264 encoder (codec h264)
+ V..... hevc_vaapi           H.265/HEVC (VAAPI) (codec hevc)
+ A....D aac                  AAC (Advanced Audio Coding)
+ A....D libopus              libopus Opus (codec opus)
+";
+
+        assert_eq!(
+            parse_encoders(list),
+            ["libx264", "h264_nvenc", "hevc_vaapi", "aac", "libopus"]
+        );
+    }
+
+    #[test]
+    fn no_encoders() {
+        assert!(parse_encoders("").is_empty());
+        assert!(parse_encoders("Encoders:\n V..... = Video\n").is_empty());
+    }
+}
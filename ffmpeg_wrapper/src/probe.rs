@@ -0,0 +1,358 @@
+//! Typed output of `ffprobe -show_format -show_streams`
+
+use crate::{Error, Result};
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Probe a media file
+pub fn probe(path: impl AsRef<Path>) -> Result<Probe> {
+    let path = path.as_ref();
+    let mut command = Command::new("ffprobe");
+
+    command
+        .args([
+            "-v",
+            "error",
+            "-of",
+            "json",
+            "-show_format",
+            "-show_streams",
+        ])
+        .arg(path)
+        .stdin(Stdio::null());
+
+    log::debug!("Executing command\n{:?}", command);
+
+    let output = command.output().map_err(|source| Error::Spawn {
+        program: "ffprobe",
+        source,
+    })?;
+
+    if !output.status.success() {
+        return Err(Error::Failed {
+            command: format!("{:?}", command),
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        });
+    }
+
+    serde_json::from_slice(&output.stdout).map_err(|source| Error::Parse {
+        path: path.display().to_string(),
+        source,
+    })
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Probe {
+    #[serde(default)]
+    pub streams: Vec<Stream>,
+    pub format: Option<Format>,
+}
+
+impl Probe {
+    /// Duration of the container, or of its longest stream when the container does not say
+    pub fn duration(&self) -> Option<Duration> {
+        self.format
+            .as_ref()
+            .and_then(|v| v.duration)
+            .or_else(|| self.streams.iter().filter_map(|v| v.duration).max())
+    }
+
+    /// The first video stream that is not a cover picture
+    pub fn video(&self) -> Option<&Stream> {
+        self.videos().next()
+    }
+
+    pub fn videos(&self) -> impl Iterator<Item = &Stream> {
+        self.streams
+            .iter()
+            .filter(|v| v.codec_type == CodecType::Video && !v.disposition.attached_pic())
+    }
+
+    pub fn audios(&self) -> impl Iterator<Item = &Stream> {
+        self.of_type(CodecType::Audio)
+    }
+
+    pub fn subtitles(&self) -> impl Iterator<Item = &Stream> {
+        self.of_type(CodecType::Subtitle)
+    }
+
+    pub fn of_type(&self, codec_type: CodecType) -> impl Iterator<Item = &Stream> {
+        self.streams
+            .iter()
+            .filter(move |v| v.codec_type == codec_type)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CodecType {
+    Video,
+    Audio,
+    Subtitle,
+    Data,
+    Attachment,
+    #[default]
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Stream {
+    pub index: usize,
+    pub codec_type: CodecType,
+    pub codec_name: Option<String>,
+    pub profile: Option<String>,
+    pub pix_fmt: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub sample_aspect_ratio: Option<String>,
+    pub display_aspect_ratio: Option<String>,
+    pub field_order: Option<String>,
+    #[serde(deserialize_with = "from_str")]
+    pub r_frame_rate: Option<Rational>,
+    #[serde(deserialize_with = "from_str")]
+    pub avg_frame_rate: Option<Rational>,
+    #[serde(deserialize_with = "from_str")]
+    pub nb_frames: Option<u64>,
+    pub channels: Option<u32>,
+    pub channel_layout: Option<String>,
+    #[serde(deserialize_with = "from_str")]
+    pub sample_rate: Option<u32>,
+    #[serde(deserialize_with = "from_str")]
+    pub bit_rate: Option<u64>,
+    #[serde(deserialize_with = "seconds")]
+    pub duration: Option<Duration>,
+    pub disposition: Disposition,
+    pub tags: HashMap<String, String>,
+}
+
+impl Stream {
+    /// Frames per second, preferring the average over the container's base rate
+    pub fn frame_rate(&self) -> Option<f64> {
+        [self.avg_frame_rate, self.r_frame_rate]
+            .into_iter()
+            .flatten()
+            .map(f64::from)
+            .find(|v| v.is_finite() && *v > 0.0)
+    }
+
+    pub fn language(&self) -> Option<&str> {
+        self.tags.get("language").map(String::as_str)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Disposition {
+    pub default: u8,
+    pub forced: u8,
+    pub attached_pic: u8,
+}
+
+impl Disposition {
+    /// The stream is the cover art of the file rather than an actual video
+    pub fn attached_pic(&self) -> bool {
+        self.attached_pic != 0
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Format {
+    pub filename: String,
+    pub format_name: String,
+    pub nb_streams: usize,
+    #[serde(deserialize_with = "seconds")]
+    pub duration: Option<Duration>,
+    #[serde(deserialize_with = "from_str")]
+    pub size: Option<u64>,
+    #[serde(deserialize_with = "from_str")]
+    pub bit_rate: Option<u64>,
+    pub tags: HashMap<String, String>,
+}
+
+/// A fraction the way ffprobe prints it, e.g. `30000/1001`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rational {
+    pub num: i64,
+    pub den: i64,
+}
+
+impl FromStr for Rational {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (num, den) = s.split_once(['/', ':']).unwrap_or((s, "1"));
+        let parse = |v: &str| v.trim().parse().map_err(|_| format!("Invalid ratio {s:?}"));
+
+        Ok(Self {
+            num: parse(num)?,
+            den: parse(den)?,
+        })
+    }
+}
+
+impl From<Rational> for f64 {
+    fn from(v: Rational) -> Self {
+        v.num as f64 / v.den as f64
+    }
+}
+
+/// ffprobe prints most of its numbers as strings, and `N/A` when it does not know them
+fn from_str<'de, D, T>(deserializer: D) -> std::result::Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+{
+    let value = Option::<String>::deserialize(deserializer)?;
+    Ok(value.and_then(|v| v.parse().ok()))
+}
+
+fn seconds<'de, D>(deserializer: D) -> std::result::Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = from_str::<D, f64>(deserializer)?;
+    Ok(value
+        .filter(|v| v.is_finite() && *v >= 0.0)
+        .map(Duration::from_secs_f64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `ffprobe -v error -of json -show_format -show_streams` of an mkv with a cover
+    const PROBE: &str = r#"{
+    "streams": [
+        {
+            "index": 0,
+            "codec_name": "h264",
+            "profile": "High",
+            "codec_type": "video",
+            "width": 1440,
+            "height": 1080,
+            "sample_aspect_ratio": "4:3",
+            "display_aspect_ratio": "16:9",
+            "pix_fmt": "yuv420p",
+            "field_order": "progressive",
+            "r_frame_rate": "24000/1001",
+            "avg_frame_rate": "24000/1001",
+            "disposition": { "default": 1, "forced": 0, "attached_pic": 0 },
+            "tags": { "language": "jpn", "DURATION": "00:23:40.002000000" }
+        },
+        {
+            "index": 1,
+            "codec_name": "aac",
+            "codec_type": "audio",
+            "sample_rate": "48000",
+            "channels": 6,
+            "channel_layout": "5.1",
+            "r_frame_rate": "0/0",
+            "avg_frame_rate": "0/0",
+            "bit_rate": "N/A",
+            "disposition": { "default": 1, "forced": 0, "attached_pic": 0 },
+            "tags": { "language": "eng" }
+        },
+        {
+            "index": 2,
+            "codec_name": "mjpeg",
+            "codec_type": "video",
+            "width": 600,
+            "height": 600,
+            "r_frame_rate": "90000/1",
+            "avg_frame_rate": "0/0",
+            "duration": "N/A",
+            "disposition": { "default": 0, "forced": 0, "attached_pic": 1 }
+        },
+        {
+            "index": 3,
+            "codec_name": "ass",
+            "codec_type": "subtitle"
+        },
+        {
+            "index": 4,
+            "codec_type": "attachment"
+        }
+    ],
+    "format": {
+        "filename": "episode.mkv",
+        "nb_streams": 5,
+        "format_name": "matroska,webm",
+        "duration": "1420.002000",
+        "size": "734003200",
+        "bit_rate": "4135217",
+        "tags": { "ENCODER": "Lavf60.3.100" }
+    }
+}"#;
+
+    #[test]
+    fn probe() {
+        let probe: Probe = serde_json::from_str(PROBE).unwrap();
+
+        assert_eq!(probe.duration(), Some(Duration::from_secs_f64(1420.002)));
+        assert_eq!(probe.videos().count(), 1);
+        assert_eq!(probe.audios().count(), 1);
+        assert_eq!(probe.subtitles().count(), 1);
+
+        let format = probe.format.as_ref().unwrap();
+        assert_eq!(format.size, Some(734_003_200));
+        assert_eq!(format.bit_rate, Some(4_135_217));
+
+        let video = probe.video().unwrap();
+        assert_eq!((video.width, video.height), (Some(1440), Some(1080)));
+        assert_eq!(video.sample_aspect_ratio.as_deref(), Some("4:3"));
+        assert_eq!(video.language(), Some("jpn"));
+        assert!((video.frame_rate().unwrap() - 23.976).abs() < 0.001);
+
+        let audio = probe.audios().next().unwrap();
+        assert_eq!(audio.sample_rate, Some(48_000));
+        assert_eq!(audio.channels, Some(6));
+        // N/A is not a number
+        assert_eq!(audio.bit_rate, None);
+        // 0/0 is no rate at all
+        assert_eq!(audio.frame_rate(), None);
+
+        let cover = &probe.streams[2];
+        assert!(cover.disposition.attached_pic());
+        assert_eq!(cover.duration, None);
+        // The average is unknown, the base rate is used instead
+        assert_eq!(cover.frame_rate(), Some(90_000.0));
+
+        assert_eq!(probe.streams[4].codec_type, CodecType::Attachment);
+    }
+
+    #[test]
+    fn rational() {
+        let ntsc = "30000/1001".parse::<Rational>().unwrap();
+        assert_eq!(
+            ntsc,
+            Rational {
+                num: 30000,
+                den: 1001
+            }
+        );
+        assert!((f64::from(ntsc) - 29.97).abs() < 0.001);
+
+        assert_eq!("16:9".parse(), Ok(Rational { num: 16, den: 9 }));
+        assert_eq!("25".parse(), Ok(Rational { num: 25, den: 1 }));
+        assert_eq!(" 4 / 3 ".parse(), Ok(Rational { num: 4, den: 3 }));
+        assert!("N/A".parse::<Rational>().is_err());
+        assert!("".parse::<Rational>().is_err());
+    }
+
+    #[test]
+    fn missing_fields() {
+        let probe: Probe = serde_json::from_str("{}").unwrap();
+
+        assert!(probe.streams.is_empty());
+        assert!(probe.format.is_none());
+        assert_eq!(probe.duration(), None);
+    }
+}
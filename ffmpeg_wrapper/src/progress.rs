@@ -0,0 +1,126 @@
+//! Parser for the `key=value` report ffmpeg writes with `-progress`
+
+use std::time::Duration;
+
+/// One block of the `-progress` report
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Progress {
+    pub frame: Option<u64>,
+    pub fps: Option<f64>,
+    /// Position in the output, comparable with the duration of the input
+    pub out_time: Option<Duration>,
+    pub total_size: Option<u64>,
+    /// Encoding speed relative to realtime, `2.0` means twice as fast as playback
+    pub speed: Option<f64>,
+    /// The last report, ffmpeg is about to exit
+    pub done: bool,
+}
+
+/// Collects the lines of a report block until its closing `progress=` line
+#[derive(Debug, Default)]
+pub struct ProgressParser {
+    current: Progress,
+}
+
+impl ProgressParser {
+    /// Feed a line of output, returns the report once its block is complete
+    pub fn feed(&mut self, line: &str) -> Option<Progress> {
+        let (key, value) = line.trim().split_once('=')?;
+        let value = value.trim();
+        let current = &mut self.current;
+
+        match key {
+            "frame" => current.frame = value.parse().ok(),
+            "fps" => current.fps = value.parse().ok(),
+            "total_size" => current.total_size = value.parse().ok(),
+            "out_time_us" | "out_time_ms" => {
+                // Both are microseconds, `out_time_ms` is misnamed in ffmpeg
+                current.out_time = value.parse().ok().map(Duration::from_micros);
+            }
+            "speed" => current.speed = value.trim_end_matches('x').trim().parse().ok(),
+            "progress" => {
+                current.done = value == "end";
+                return Some(std::mem::take(current));
+            }
+            _ => {}
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two blocks of `ffmpeg -progress pipe:1`, the last one closing the report
+    const REPORT: &str = "\
+frame=240
+fps=47.91
+stream_0_0_q=28.0
+bitrate=1043.2kbits/s
+total_size=1310768
+out_time_us=10052000
+out_time_ms=10052000
+out_time=00:00:10.052000
+dup_frames=0
+drop_frames=0
+speed=2.01x
+progress=continue
+frame=480
+fps=48.02
+stream_0_0_q=-1.0
+bitrate=1024.5kbits/s
+total_size=2576431
+out_time_us=20117000
+out_time_ms=20117000
+out_time=00:00:20.117000
+dup_frames=0
+drop_frames=0
+speed=N/A
+progress=end
+";
+
+    #[test]
+    fn report() {
+        let mut parser = ProgressParser::default();
+        let blocks = REPORT
+            .lines()
+            .filter_map(|v| parser.feed(v))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            blocks,
+            [
+                Progress {
+                    frame: Some(240),
+                    fps: Some(47.91),
+                    out_time: Some(Duration::from_micros(10_052_000)),
+                    total_size: Some(1_310_768),
+                    speed: Some(2.01),
+                    done: false,
+                },
+                Progress {
+                    frame: Some(480),
+                    fps: Some(48.02),
+                    out_time: Some(Duration::from_micros(20_117_000)),
+                    total_size: Some(2_576_431),
+                    speed: None,
+                    done: true,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_time() {
+        let mut parser = ProgressParser::default();
+
+        assert_eq!(parser.feed("out_time_us=N/A"), None);
+        assert_eq!(parser.feed("not a report line"), None);
+
+        let block = parser.feed("progress=continue").unwrap();
+        assert_eq!(block.out_time, None);
+        assert!(!block.done);
+    }
+}
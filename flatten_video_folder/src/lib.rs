@@ -1,9 +1,10 @@
 use anyhow::{bail, Result};
 use clap::*;
 use infer::MatcherType;
+use macros::plan::Plan;
 use std::ffi::OsStr;
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 
 // From most to least
 const COVER_NAME_PRIORITY: &[&str] = &["folder", "backdrop", "cover"];
@@ -26,16 +27,18 @@ impl Args {
                 Err(_) => false,
             });
 
+        let mut plan = Plan::new();
+
         for entry in dir {
-            if let Err(why) = self.process_dir(entry.path()) {
+            if let Err(why) = self.process_dir(entry.path(), &mut plan) {
                 log::error!("Error why processing a directory\n{:#?}", why);
             }
         }
 
-        Ok(())
+        plan.execute_reported()
     }
 
-    fn process_dir(&self, path: PathBuf) -> Result<()> {
+    fn process_dir(&self, path: PathBuf, plan: &mut Plan) -> Result<()> {
         let Some(parent) = path.parent() else {
             bail!("Cannot get parent of the directory {:#?}", path);
         };
@@ -103,7 +106,7 @@ impl Args {
         if let Some(filename) = first.file_name() {
             let mut new_path = parent.join(filename);
             set_file_stem(&mut new_path, dir_name);
-            rename_guard(first, new_path);
+            plan.rename(first, new_path);
         }
 
         for (video, i) in iter.zip(2..) {
@@ -116,14 +119,14 @@ impl Args {
                 &mut new_path,
                 format!("{}_{}", dir_name.to_string_lossy(), i),
             );
-            rename_guard(video, new_path);
+            plan.rename(video, new_path);
         }
 
         if let Some(cover) = cover_image {
             if let Some(filename) = cover.file_name() {
                 let mut new_path = parent.join(filename);
                 set_file_stem(&mut new_path, dir_name);
-                rename_guard(cover, new_path);
+                plan.rename(cover, new_path);
             }
         }
 
@@ -143,13 +146,3 @@ fn set_file_stem(path: &mut PathBuf, name: impl AsRef<OsStr>) {
         ext.to_string_lossy()
     ));
 }
-
-fn rename_guard(from: impl AsRef<Path>, to: impl AsRef<Path>) {
-    let from = from.as_ref();
-    let to = to.as_ref();
-
-    log::info!("Moving {:?} to {:?}", from, to);
-    if let Err(why) = fs::rename(from, to) {
-        log::error!("Cannot move {:#?} to {:#?}\n {:#?}", from, to, why);
-    }
-}
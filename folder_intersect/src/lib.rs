@@ -1,4 +1,5 @@
 use clap::*;
+use macros::plan::Plan;
 use std::collections::HashSet;
 use std::fs;
 use std::path::{Path, PathBuf};
@@ -29,10 +30,6 @@ impl Folder {
 
         let target_path = target_path.map(|v| path.join(v));
 
-        if let Some(ref target_path) = target_path {
-            fs::create_dir_all(&target_path).ok();
-        }
-
         Ok(Self {
             path,
             target_path,
@@ -40,14 +37,11 @@ impl Folder {
         })
     }
 
-    pub fn move_entry(&self, entry_name: &str) -> anyhow::Result<()> {
+    pub fn move_entry(&self, entry_name: &str, plan: &mut Plan) {
         if let Some(ref target_path) = self.target_path {
-            let source_path = self.path.join(entry_name);
-            let target_path = target_path.join(entry_name);
-            fs::rename(&source_path, &target_path)?;
+            plan.mkdir(target_path);
+            plan.rename(self.path.join(entry_name), target_path.join(entry_name));
         }
-
-        Ok(())
     }
 }
 
@@ -59,6 +53,8 @@ impl Args {
             .filter_map(|d| Folder::new(d, self.move_to.as_deref()).ok())
             .collect::<Vec<_>>();
 
+        let mut plan = Plan::new();
+
         for i in 0..list.len() {
             for j in (i + 1)..list.len() {
                 let common: Vec<_> = list[i]
@@ -75,8 +71,8 @@ impl Args {
 
                     for name in &common {
                         log::info!("  {}", name);
-                        list[i].move_entry(name)?;
-                        list[j].move_entry(name)?;
+                        list[i].move_entry(name, &mut plan);
+                        list[j].move_entry(name, &mut plan);
                     }
 
                     println!();
@@ -84,6 +80,6 @@ impl Args {
             }
         }
 
-        Ok(())
+        plan.execute_reported()
     }
 }
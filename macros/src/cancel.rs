@@ -0,0 +1,244 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, ExitStatus};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// How often a child process is checked while waiting for it
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Catch Ctrl-C and termination requests.
+///
+/// The first one only raises the flag so the tool can stop between items and clean up after
+/// itself, the second one exits right away.
+pub fn install() {
+    let res = ctrlc::set_handler(|| {
+        if CANCELLED.swap(true, Ordering::SeqCst) {
+            eprintln!("Cancelled twice, exiting now");
+            process::exit(130);
+        }
+
+        log::warn!("Cancelling, cleaning up the unfinished work. Press Ctrl-C again to exit now");
+    });
+
+    if let Err(why) = res {
+        log::debug!("Cannot install the Ctrl-C handler\n{:#?}", why);
+    }
+}
+
+pub fn is_cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+/// Fail with [`Cancelled`] once the run has been cancelled, for the checks between items
+pub fn check() -> Result<(), Cancelled> {
+    match is_cancelled() {
+        true => Err(Cancelled),
+        false => Ok(()),
+    }
+}
+
+/// The error of work stopped by a cancellation
+#[derive(Debug, Clone, Copy)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Wait for a child process, killing it when the run gets cancelled
+pub fn wait(child: &mut Child) -> io::Result<ExitStatus> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+
+        if is_cancelled() {
+            child.kill().ok();
+            child.wait()?;
+            return Err(io::Error::new(io::ErrorKind::Interrupted, Cancelled));
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// An output file that is being written.
+///
+/// The file is written under a temporary name next to where it goes, and only moved into place
+/// by [`Partial::finish`]. Otherwise the temporary file is removed when the guard goes out of
+/// scope, so a failed or cancelled item never leaves a truncated file that looks valid. The guard
+/// never removes a file it did not create.
+#[derive(Debug)]
+pub struct Partial {
+    target: PathBuf,
+    path: PathBuf,
+    replace: bool,
+    finished: bool,
+}
+
+impl Partial {
+    /// Fails when there is a file at `target` already, it is never overwritten
+    pub fn new(target: impl Into<PathBuf>) -> io::Result<Self> {
+        let mut partial = Self::replacing(target);
+
+        if fs::symlink_metadata(&partial.target).is_ok() {
+            return Err(already_exists(&partial.target));
+        }
+
+        partial.replace = false;
+        Ok(partial)
+    }
+
+    /// Replace whatever is at `target` once the output is finished
+    pub fn replacing(target: impl Into<PathBuf>) -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let target = target.into();
+        let stem = target.file_stem().unwrap_or_default().to_string_lossy();
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+
+        // Hidden, and with the extension the writers may pick their format by
+        let mut name = format!(".{stem}.part-{}-{count}", process::id());
+
+        if let Some(ext) = target.extension() {
+            name = format!("{name}.{}", ext.to_string_lossy());
+        }
+
+        Self {
+            path: target.with_file_name(name),
+            target,
+            replace: true,
+            finished: false,
+        }
+    }
+
+    /// Where the output is written until it is finished
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Where the output goes once it is finished
+    pub fn target(&self) -> &Path {
+        &self.target
+    }
+
+    /// The output is complete, move it into place
+    pub fn finish(mut self) -> io::Result<PathBuf> {
+        if !self.replace && fs::symlink_metadata(&self.target).is_ok() {
+            return Err(already_exists(&self.target));
+        }
+
+        fs::rename(&self.path, &self.target)?;
+        self.finished = true;
+        Ok(std::mem::take(&mut self.target))
+    }
+}
+
+impl Drop for Partial {
+    fn drop(&mut self) {
+        if self.finished || !self.path.exists() {
+            return;
+        }
+
+        log::warn!("Removing the unfinished output {:?}", self.path);
+
+        if let Err(why) = fs::remove_file(&self.path) {
+            log::error!("Cannot remove {:?}\n{:#?}", self.path, why);
+        }
+    }
+}
+
+fn already_exists(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("{:?} exists already", path),
+    )
+}
+
+/// Print what has been finished before the run got cancelled
+pub fn summary<T: fmt::Debug>(finished: &[T], total: usize) {
+    log::warn!("Cancelled after finishing {} of {total}", finished.len());
+
+    for item in finished {
+        log::info!("Finished {:?}", item);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finish() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("video.mp4");
+
+        let partial = Partial::new(&target).unwrap();
+        assert_ne!(partial.path(), target);
+        assert_eq!(partial.path().extension().unwrap(), "mp4");
+
+        fs::write(partial.path(), "done").unwrap();
+        let tmp = partial.path().to_owned();
+
+        assert_eq!(partial.finish().unwrap(), target);
+        assert_eq!(fs::read_to_string(&target).unwrap(), "done");
+        assert!(!tmp.exists());
+    }
+
+    #[test]
+    fn unfinished() {
+        let dir = tempfile::tempdir().unwrap();
+        let partial = Partial::new(dir.path().join("video.mp4")).unwrap();
+        fs::write(partial.path(), "half").unwrap();
+        drop(partial);
+
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn existing() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("video.mp4");
+        fs::write(&target, "original").unwrap();
+
+        let err = Partial::new(&target).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&target).unwrap(), "original");
+
+        // A failed output leaves the file it would have replaced alone
+        let partial = Partial::replacing(&target);
+        fs::write(partial.path(), "half").unwrap();
+        drop(partial);
+        assert_eq!(fs::read_to_string(&target).unwrap(), "original");
+
+        let partial = Partial::replacing(&target);
+        fs::write(partial.path(), "new").unwrap();
+        partial.finish().unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "new");
+    }
+
+    #[test]
+    fn appeared() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("video.mp4");
+
+        let partial = Partial::new(&target).unwrap();
+        fs::write(partial.path(), "new").unwrap();
+        fs::write(&target, "other").unwrap();
+
+        let err = partial.finish().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&target).unwrap(), "other");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
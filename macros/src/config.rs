@@ -0,0 +1,271 @@
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Command};
+use directories::ProjectDirs;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+/// Per-user configuration, inside the XDG config directory
+pub const CONFIG_FILE: &str = "config.toml";
+
+/// Project-local configuration, looked up from the current directory upward
+pub const LOCAL_CONFIG_FILE: &str = ".tmoutils.toml";
+
+/// Defaults for the tool options, loaded from the configuration files.
+///
+/// Every file has a section per tool (`[to_720p]`, `[renamer]`, ...) whose keys are the long
+/// option names of that tool. The values become the defaults of the clap command, so the
+/// command line always wins, then the local file, then the user file.
+#[derive(Debug, Default)]
+pub struct Config {
+    /// From the lowest to the highest priority
+    sources: Vec<(PathBuf, Table)>,
+    errors: Vec<String>,
+}
+
+impl Config {
+    pub fn load() -> Self {
+        let mut config = Self::default();
+
+        let user =
+            ProjectDirs::from("", "tmokenc", "tmoutils").map(|v| v.config_dir().join(CONFIG_FILE));
+
+        let local = std::env::current_dir().ok().and_then(|dir| {
+            dir.ancestors()
+                .map(|v| v.join(LOCAL_CONFIG_FILE))
+                .find(|v| v.is_file())
+        });
+
+        for path in [user, local].into_iter().flatten() {
+            config.load_file(path);
+        }
+
+        config
+    }
+
+    fn load_file(&mut self, path: PathBuf) {
+        let content = match fs::read_to_string(&path) {
+            Ok(v) => v,
+            Err(_) if !path.exists() => return,
+            Err(why) => return self.errors.push(format!("Cannot read {:?}: {why}", path)),
+        };
+
+        match content.parse::<Table>() {
+            Ok(table) => self.sources.push((path, table)),
+            Err(why) => self.errors.push(format!("Cannot parse {:?}: {why}", path)),
+        }
+    }
+
+    fn sections<'a>(&'a self, tool: &str) -> impl Iterator<Item = (&'a Path, &'a Table)> {
+        let tool = tool.to_owned();
+
+        self.sources.iter().filter_map(move |(path, table)| {
+            let section = table.get(&tool)?.as_table()?;
+            Some((path.as_path(), section))
+        })
+    }
+
+    /// Use the configured values as the defaults of the tool command
+    pub fn apply(&self, mut command: Command) -> Command {
+        let tool = command.get_name().to_owned();
+
+        for (_, section) in self.sections(&tool) {
+            for (key, value) in section {
+                let id = arg_id(key);
+
+                if !command.get_arguments().any(|v| v.get_id() == id.as_str()) {
+                    continue;
+                }
+
+                let Some(values) = to_strings(value) else {
+                    continue;
+                };
+
+                command = command.mut_arg(id, |arg| arg.default_values(values).required(false));
+            }
+        }
+
+        command
+    }
+
+    /// Log the files that could not be loaded and the keys that match no option of the tool
+    pub fn check(&self, tool: &str, command: &Command) {
+        for why in &self.errors {
+            log::warn!("{why}");
+        }
+
+        for (path, section) in self.sections(tool) {
+            for (key, value) in section {
+                let id = arg_id(key);
+
+                if !command.get_arguments().any(|v| v.get_id() == id.as_str()) {
+                    log::warn!("{:?}: `{tool}` has no option `{key}`", path);
+                } else if to_strings(value).is_none() {
+                    log::warn!(
+                        "{:?}: `{tool}.{key}` must be a string, number or boolean",
+                        path
+                    );
+                }
+            }
+        }
+    }
+
+    /// The file that sets the default of an option, the one with the highest priority
+    fn origin(&self, tool: &str, id: &str) -> Option<&Path> {
+        self.sections(tool)
+            .filter(|(_, section)| section.keys().any(|key| arg_id(key) == id))
+            .map(|(path, _)| path)
+            .last()
+    }
+
+    /// Print every option with its effective value and where that value comes from
+    pub fn print(&self, tool: &str, command: &Command, matches: &ArgMatches) {
+        for (path, _) in &self.sources {
+            println!("# {}", path.display());
+        }
+
+        println!("[{tool}]");
+
+        let ids = command
+            .get_arguments()
+            .map(|v| v.get_id().as_str())
+            .filter(|&v| v != "print_config" && matches.value_source(v).is_some());
+
+        for id in ids {
+            let values = matches
+                .get_raw(id)
+                .into_iter()
+                .flatten()
+                .map(|v| format!("{:?}", v.to_string_lossy()))
+                .collect::<Vec<_>>();
+
+            let value = match values.len() {
+                1 => values[0].to_owned(),
+                _ => format!("[{}]", values.join(", ")),
+            };
+
+            let source = match matches.value_source(id) {
+                Some(ValueSource::CommandLine) => "command line".to_owned(),
+                Some(ValueSource::EnvVariable) => "environment".to_owned(),
+                Some(ValueSource::DefaultValue) => match self.origin(tool, id) {
+                    Some(path) => path.display().to_string(),
+                    None => "default".to_owned(),
+                },
+                _ => continue,
+            };
+
+            println!("{id} = {value} # {source}");
+        }
+    }
+}
+
+/// Options can be written the way they are typed (`frame-rate`) or as the clap id (`frame_rate`)
+fn arg_id(key: &str) -> String {
+    key.replace('-', "_")
+}
+
+fn to_strings(value: &Value) -> Option<Vec<String>> {
+    match value {
+        Value::String(v) => Some(vec![v.to_owned()]),
+        Value::Integer(v) => Some(vec![v.to_string()]),
+        Value::Float(v) => Some(vec![v.to_string()]),
+        Value::Boolean(v) => Some(vec![v.to_string()]),
+        Value::Array(values) => values
+            .iter()
+            .map(|v| {
+                to_strings(v)
+                    .filter(|v| v.len() == 1)
+                    .map(|mut v| v.remove(0))
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{Arg, ArgAction};
+
+    fn config(files: &[&str]) -> Config {
+        let sources = files
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (PathBuf::from(i.to_string()), v.parse().unwrap()))
+            .collect();
+
+        Config {
+            sources,
+            errors: Vec::new(),
+        }
+    }
+
+    fn command() -> Command {
+        Command::new("tool")
+            .arg(Arg::new("path").required(true))
+            .arg(Arg::new("frame_rate").long("frame-rate"))
+            .arg(Arg::new("ext").long("ext").action(ArgAction::Append))
+            .arg(Arg::new("force").long("force").action(ArgAction::SetTrue))
+    }
+
+    fn parse(config: &Config, args: &[&str]) -> ArgMatches {
+        let args = ["tool"].iter().chain(args);
+        config.apply(command()).try_get_matches_from(args).unwrap()
+    }
+
+    #[test]
+    fn defaults() {
+        let config = config(&[
+            "[tool]\nframe-rate = 24\next = [\"mkv\", \"mp4\"]\nforce = true\npath = \".\"",
+            "[other]\nframe_rate = 60",
+        ]);
+
+        let matches = parse(&config, &[]);
+
+        assert_eq!(matches.get_one::<String>("frame_rate").unwrap(), "24");
+        assert_eq!(matches.get_one::<String>("path").unwrap(), ".");
+        assert!(matches.get_flag("force"));
+
+        let exts = matches.get_many::<String>("ext").unwrap();
+        assert_eq!(exts.collect::<Vec<_>>(), ["mkv", "mp4"]);
+    }
+
+    #[test]
+    fn priority() {
+        let config = config(&["[tool]\nframe_rate = 24", "[tool]\nframe_rate = 30"]);
+
+        let matches = parse(&config, &["."]);
+        assert_eq!(matches.get_one::<String>("frame_rate").unwrap(), "30");
+        assert_eq!(config.origin("tool", "frame_rate"), Some(Path::new("1")));
+
+        let matches = parse(&config, &[".", "--frame-rate", "60"]);
+        assert_eq!(matches.get_one::<String>("frame_rate").unwrap(), "60");
+    }
+
+    #[test]
+    fn values() {
+        let value = |v: &str| to_strings(&format!("v = {v}").parse::<Table>().unwrap()["v"]);
+
+        assert_eq!(value("\"a\""), Some(vec!["a".to_owned()]));
+        assert_eq!(value("1.5"), Some(vec!["1.5".to_owned()]));
+        assert_eq!(value("false"), Some(vec!["false".to_owned()]));
+        assert_eq!(value("[1, 2]"), Some(vec!["1".to_owned(), "2".to_owned()]));
+        assert_eq!(value("[[1]]"), Some(vec!["1".to_owned()]));
+        assert_eq!(value("[[1, 2]]"), None);
+        assert_eq!(value("{ a = 1 }"), None);
+    }
+
+    #[test]
+    fn invalid() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CONFIG_FILE);
+        fs::write(&path, "[tool\n").unwrap();
+
+        let mut config = Config::default();
+        config.load_file(path);
+        config.load_file(dir.path().join("missing.toml"));
+
+        assert!(config.sources.is_empty());
+        assert_eq!(config.errors.len(), 1);
+    }
+}
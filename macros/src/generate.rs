@@ -0,0 +1,87 @@
+use anyhow::Result;
+use clap::{value_parser, Arg, ArgMatches, Command, ValueHint};
+use clap_complete::Shell;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// Arguments holding a directory or a file in the tools, for a better completion than plain text
+const DIR_ARGS: &[&str] = &["location", "path", "output_dir"];
+const FILE_ARGS: &[&str] = &["input", "output_file"];
+
+/// The hidden `completions` and `man` subcommands
+pub fn subcommands() -> [Command; 2] {
+    [
+        Command::new("completions")
+            .about("Print the shell completion script")
+            .hide(true)
+            .arg(
+                Arg::new("shell")
+                    .required(true)
+                    .value_parser(value_parser!(Shell)),
+            ),
+        Command::new("man")
+            .about("Print the man page, or write the pages of every subcommand into a directory")
+            .hide(true)
+            .arg(
+                Arg::new("out_dir")
+                    .long("out-dir")
+                    .value_hint(ValueHint::DirPath)
+                    .value_parser(value_parser!(PathBuf)),
+            ),
+    ]
+}
+
+/// Run the `completions` or `man` subcommand of `command` if that is what was asked for
+pub fn handle(command: &Command, matches: &ArgMatches) -> Option<Result<()>> {
+    let (name, matches) = matches
+        .subcommand()
+        .filter(|(name, _)| matches!(*name, "completions" | "man"))?;
+
+    let mut command = hint_paths(command.clone());
+    let bin_name = command.get_name().to_owned();
+
+    let res = match name {
+        "completions" => {
+            let shell = *matches.get_one::<Shell>("shell").expect("required");
+            clap_complete::generate(shell, &mut command, bin_name, &mut io::stdout());
+            Ok(())
+        }
+        "man" => match matches.get_one::<PathBuf>("out_dir") {
+            Some(dir) => {
+                fs::create_dir_all(dir).and_then(|_| clap_mangen::generate_to(command, dir))
+            }
+            None => clap_mangen::Man::new(command).render(&mut io::stdout()),
+        },
+        _ => unreachable!(),
+    };
+
+    Some(res.map_err(Into::into))
+}
+
+fn hint_paths(mut command: Command) -> Command {
+    let ids = command
+        .get_arguments()
+        .filter(|v| v.get_value_hint() == ValueHint::Unknown && v.get_possible_values().is_empty())
+        .map(|v| v.get_id().to_string())
+        .collect::<Vec<_>>();
+
+    for id in ids {
+        if DIR_ARGS.contains(&id.as_str()) {
+            command = command.mut_arg(id, |v| v.value_hint(ValueHint::DirPath));
+        } else if FILE_ARGS.contains(&id.as_str()) {
+            command = command.mut_arg(id, |v| v.value_hint(ValueHint::FilePath));
+        }
+    }
+
+    let subcommands = command
+        .get_subcommands()
+        .map(|v| v.get_name().to_owned())
+        .collect::<Vec<_>>();
+
+    for name in subcommands {
+        command = command.mut_subcommand(name, hint_paths);
+    }
+
+    command
+}
@@ -0,0 +1,805 @@
+use crate::plan::Op;
+use anyhow::{bail, Context as _, Result};
+use clap::Parser;
+use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, BufRead as _, BufReader, Write as _};
+use std::path::{self, Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Trash directory at the top of a filesystem other than the one holding the data directory
+pub const TRASH_DIR: &str = ".tmoutils-trash";
+
+/// How long the trashed files are kept unless `--trash-days` says otherwise
+const TRASH_DAYS: u64 = 7;
+
+/// How many GiB the trash holds at most unless `--trash-size` says otherwise
+const TRASH_SIZE: u64 = 10;
+
+const GIB: u64 = 1024 * 1024 * 1024;
+
+const JOURNAL_EXT: &str = "jsonl";
+const UNDONE_EXT: &str = "undone";
+
+static SESSION: Mutex<Option<Session>> = Mutex::new(None);
+
+/// Size and modification time of a path, used to tell whether it changed since
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    dir: bool,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl Fingerprint {
+    /// Directories only record that they are directories, their modification time changes
+    /// whenever something is moved in or out of them
+    fn of(path: &Path) -> Option<Self> {
+        let metadata = fs::symlink_metadata(path).ok()?;
+
+        if metadata.is_dir() {
+            return Some(Self {
+                dir: true,
+                len: 0,
+                modified: None,
+            });
+        }
+
+        Some(Self {
+            dir: false,
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    session: String,
+    tool: String,
+    args: Vec<String>,
+}
+
+/// A mutation that has been applied, with everything needed to revert it
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Record {
+    Move {
+        from: PathBuf,
+        to: PathBuf,
+        fingerprint: Fingerprint,
+        replaced: Option<PathBuf>,
+    },
+    /// Covers both copy and write, reverting means removing the new file
+    Create {
+        path: PathBuf,
+        fingerprint: Fingerprint,
+        replaced: Option<PathBuf>,
+    },
+    Delete {
+        path: PathBuf,
+        trash: PathBuf,
+    },
+    /// Directories that did not exist before, from the outermost one
+    Mkdir {
+        created: Vec<PathBuf>,
+    },
+}
+
+impl Record {
+    fn paths(&self) -> Vec<&Path> {
+        match self {
+            Self::Move { from, to, .. } => vec![from, to],
+            Self::Create { path, .. } | Self::Delete { path, .. } => vec![path],
+            Self::Mkdir { created } => created.iter().map(PathBuf::as_path).collect(),
+        }
+    }
+
+    /// Whether both records work on the same path, or inside the directory of one another
+    fn overlaps(&self, other: &Self) -> bool {
+        let others = other.paths();
+
+        self.paths()
+            .iter()
+            .any(|a| others.iter().any(|b| a.starts_with(b) || b.starts_with(a)))
+    }
+
+    fn check(&self) -> Result<()> {
+        let unchanged = |path: &Path, fingerprint: &Fingerprint| {
+            if Fingerprint::of(path).as_ref() != Some(fingerprint) {
+                bail!("{:?} has changed", path);
+            }
+
+            Ok(())
+        };
+
+        let vacant = |path: &Path| {
+            if fs::symlink_metadata(path).is_ok() {
+                bail!("{:?} exists", path);
+            }
+
+            Ok(())
+        };
+
+        let trashed = |trash: &Option<PathBuf>| match trash {
+            Some(trash) if fs::symlink_metadata(trash).is_err() => {
+                bail!("{:?} is no longer in the trash", trash)
+            }
+            _ => Ok(()),
+        };
+
+        match self {
+            Self::Move {
+                from,
+                to,
+                fingerprint,
+                replaced,
+            } => {
+                unchanged(to, fingerprint)?;
+                vacant(from)?;
+                trashed(replaced)
+            }
+            Self::Create {
+                path,
+                fingerprint,
+                replaced,
+            } => {
+                unchanged(path, fingerprint)?;
+                trashed(replaced)
+            }
+            Self::Delete { path, trash } => {
+                vacant(path)?;
+                trashed(&Some(trash.to_owned()))
+            }
+            Self::Mkdir { .. } => Ok(()),
+        }
+    }
+
+    fn revert(&self) -> io::Result<()> {
+        match self {
+            Self::Move {
+                from, to, replaced, ..
+            } => {
+                fs::rename(to, from)?;
+                restore(replaced, to)
+            }
+            Self::Create { path, replaced, .. } => {
+                fs::remove_file(path)?;
+                restore(replaced, path)
+            }
+            Self::Delete { path, trash } => fs::rename(trash, path),
+            Self::Mkdir { created } => {
+                for dir in created.iter().rev() {
+                    if let Err(why) = fs::remove_dir(dir) {
+                        log::warn!("Keeping directory {:?}\n{:#?}", dir, why);
+                    }
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Trashed files kept alive by this record
+    fn trash(&self) -> Option<&Path> {
+        match self {
+            Self::Move { replaced, .. } | Self::Create { replaced, .. } => replaced.as_deref(),
+            Self::Delete { trash, .. } => Some(trash),
+            Self::Mkdir { .. } => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Record {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Move { from, to, .. } => write!(f, "move {:?} => {:?}", to, from),
+            Self::Create { path, .. } => write!(f, "remove {:?}", path),
+            Self::Delete { path, trash } => write!(f, "restore {:?} => {:?}", trash, path),
+            Self::Mkdir { created } => write!(f, "rmdir {:?}", created),
+        }
+    }
+}
+
+struct Session {
+    id: String,
+    file: fs::File,
+    trashed: usize,
+    /// Bytes moved into the trash by this session
+    trashed_bytes: u64,
+    /// Trash directories already told about in the log
+    announced: Vec<PathBuf>,
+}
+
+impl Session {
+    fn open() -> Result<Self> {
+        let dir = journal_dir()?;
+        fs::create_dir_all(&dir)?;
+
+        if let Err(why) = prune() {
+            log::warn!("Cannot purge the old trashed files\n{:#?}", why);
+        }
+
+        let id = format!(
+            "{}-{}",
+            chrono::Local::now().format("%Y%m%d-%H%M%S"),
+            std::process::id()
+        );
+
+        let mut file = fs::File::create(dir.join(&id).with_extension(JOURNAL_EXT))?;
+        let header = Header {
+            session: id.to_owned(),
+            tool: crate::tool_name().to_owned(),
+            args: std::env::args().collect(),
+        };
+
+        writeln!(file, "{}", serde_json::to_string(&header)?)?;
+        log::debug!("Journal session {id}");
+
+        Ok(Self {
+            id,
+            file,
+            trashed: 0,
+            trashed_bytes: 0,
+            announced: Vec::new(),
+        })
+    }
+
+    fn append(&mut self, record: &Record) -> Result<()> {
+        writeln!(self.file, "{}", serde_json::to_string(record)?)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Move an existing path out of the way into the trash, `Ok(None)` when there is nothing
+    /// there. Fails when the path cannot be kept, it can only be deleted for good then
+    fn stash(&mut self, path: &Path) -> io::Result<Option<PathBuf>> {
+        if fs::symlink_metadata(path).is_err() {
+            return Ok(None);
+        }
+
+        if crate::global().no_trash {
+            return Err(io::Error::other("--no-trash is set"));
+        }
+
+        let size = disk_size(path);
+        let max_size = crate::global().trash_size.unwrap_or(TRASH_SIZE) * GIB;
+
+        if self.trashed_bytes + size > max_size {
+            return Err(io::Error::other("the trash would go over --trash-size"));
+        }
+
+        let dir = trash_dir(path)?;
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let trash = dir.join(&self.id);
+
+        fs::create_dir_all(&trash)?;
+
+        let trash = trash.join(format!("{}-{}", self.trashed, name));
+        fs::rename(path, &trash)?;
+        self.trashed += 1;
+        self.trashed_bytes += size;
+
+        if !self.announced.contains(&dir) {
+            log::info!(
+                "Deleted and replaced files go into the trash at {:?}, `tmoutils undo --purge` empties it",
+                dir
+            );
+            self.announced.push(dir);
+        }
+
+        Ok(Some(trash))
+    }
+
+    /// The file `op` replaces or deletes at `path` could not be trashed. Only with `--no-trash` is
+    /// it applied anyway, without a way back
+    fn irreversible(&self, op: &Op, path: &Path, why: io::Error) -> io::Result<Option<Record>> {
+        if !crate::global().no_trash {
+            return Err(io::Error::other(format!(
+                "Cannot move {:?} into the trash, use --no-trash to lose it for good: {why}",
+                path
+            )));
+        }
+
+        op.apply()?;
+        Ok(None)
+    }
+
+    /// The record of the applied operation, `None` when it cannot be undone
+    fn apply(&mut self, op: &Op) -> io::Result<Option<Record>> {
+        let record = match op {
+            Op::Move { from, to } => {
+                let replaced = match self.stash(to) {
+                    Ok(v) => v,
+                    Err(why) => return self.irreversible(op, to, why),
+                };
+
+                op.apply()?;
+                Record::Move {
+                    from: path::absolute(from)?,
+                    to: path::absolute(to)?,
+                    fingerprint: fingerprint(to)?,
+                    replaced,
+                }
+            }
+            Op::Copy { to: path, .. } | Op::Write { path, .. } => {
+                let replaced = match self.stash(path) {
+                    Ok(v) => v,
+                    Err(why) => return self.irreversible(op, path, why),
+                };
+
+                op.apply()?;
+                Record::Create {
+                    path: path::absolute(path)?,
+                    fingerprint: fingerprint(path)?,
+                    replaced,
+                }
+            }
+            Op::Delete { path } => {
+                let trash = match self.stash(path) {
+                    Ok(v) => v.ok_or(io::ErrorKind::NotFound)?,
+                    Err(why) => return self.irreversible(op, path, why),
+                };
+
+                Record::Delete {
+                    path: path::absolute(path)?,
+                    trash,
+                }
+            }
+            Op::Mkdir { path } => {
+                let path = path::absolute(path)?;
+                let created = path
+                    .ancestors()
+                    .take_while(|v| !v.exists())
+                    .map(Path::to_path_buf)
+                    .collect::<Vec<_>>();
+
+                op.apply()?;
+                Record::Mkdir {
+                    created: created.into_iter().rev().collect(),
+                }
+            }
+        };
+
+        Ok(Some(record))
+    }
+}
+
+/// Apply an operation and append it to the journal of the current session.
+///
+/// When the journal cannot be written the operation is still applied, without a way back.
+pub fn apply(op: &Op) -> io::Result<()> {
+    let mut session = SESSION.lock().unwrap_or_else(|v| v.into_inner());
+
+    if session.is_none() {
+        match Session::open() {
+            Ok(v) => *session = Some(v),
+            Err(why) => log::warn!("Cannot open the undo journal\n{:#?}", why),
+        }
+    }
+
+    let Some(session) = session.as_mut() else {
+        return op.apply();
+    };
+
+    let Some(record) = session.apply(op)? else {
+        return Ok(());
+    };
+
+    if let Err(why) = session.append(&record) {
+        log::warn!("Cannot write {op} into the undo journal\n{:#?}", why);
+    }
+
+    Ok(())
+}
+
+fn data_dir() -> io::Result<PathBuf> {
+    #[cfg(test)]
+    if let Some(dir) = tests::DATA_DIR.with(|v| v.borrow().clone()) {
+        return Ok(dir);
+    }
+
+    ProjectDirs::from("", "tmokenc", "tmoutils")
+        .map(|v| v.data_dir().to_path_buf())
+        .ok_or_else(|| io::Error::other("Cannot get the project directory"))
+}
+
+fn journal_dir() -> io::Result<PathBuf> {
+    data_dir().map(|v| v.join("journal"))
+}
+
+/// Trashing has to be a rename, so it must stay on the filesystem of the trashed path. That is
+/// the data directory when possible, otherwise the top most directory of that filesystem.
+#[cfg(unix)]
+fn trash_dir(path: &Path) -> io::Result<PathBuf> {
+    use std::os::unix::fs::MetadataExt;
+
+    let path = path::absolute(path)?;
+    let device = fs::symlink_metadata(&path)?.dev();
+    let same_device = |p: &Path| fs::metadata(p).map(|v| v.dev() == device).unwrap_or(false);
+
+    let data_trash = data_dir()?.join("trash");
+    fs::create_dir_all(&data_trash)?;
+
+    if same_device(&data_trash) {
+        return Ok(data_trash);
+    }
+
+    let top = path
+        .ancestors()
+        .skip(1)
+        .take_while(|v| same_device(v))
+        .last()
+        .unwrap_or(Path::new("."));
+
+    Ok(top.join(TRASH_DIR))
+}
+
+#[cfg(not(unix))]
+fn trash_dir(path: &Path) -> io::Result<PathBuf> {
+    let parent = path.parent().unwrap_or(Path::new("."));
+    Ok(parent.join(TRASH_DIR))
+}
+
+/// Purge the trash of the sessions older than `--trash-days`, then of the oldest ones until the
+/// rest fits in `--trash-size`
+fn prune() -> Result<()> {
+    let global = crate::global();
+    let max_age = Duration::from_secs(global.trash_days.unwrap_or(TRASH_DAYS) * 24 * 60 * 60);
+    let max_size = global.trash_size.unwrap_or(TRASH_SIZE) * GIB;
+    let mut kept = 0;
+
+    for journal in Journal::list()? {
+        let age = fs::metadata(&journal.path)?
+            .modified()?
+            .elapsed()
+            .unwrap_or_default();
+
+        let size = journal
+            .records
+            .iter()
+            .filter_map(Record::trash)
+            .map(disk_size)
+            .sum::<u64>();
+
+        if age <= max_age && kept + size <= max_size {
+            kept += size;
+            continue;
+        }
+
+        log::info!(
+            "Purging the trash of session {}, it cannot be undone anymore",
+            journal.header.session
+        );
+
+        journal.purge()?;
+    }
+
+    Ok(())
+}
+
+/// Bytes taken by a file, or by everything inside a directory
+fn disk_size(path: &Path) -> u64 {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return 0;
+    };
+
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+
+    fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .map(|v| disk_size(&v.path()))
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+fn fingerprint(path: &Path) -> io::Result<Fingerprint> {
+    Fingerprint::of(path).ok_or_else(|| io::ErrorKind::NotFound.into())
+}
+
+fn restore(trash: &Option<PathBuf>, path: &Path) -> io::Result<()> {
+    match trash {
+        Some(trash) => fs::rename(trash, path),
+        None => Ok(()),
+    }
+}
+
+struct Journal {
+    path: PathBuf,
+    header: Header,
+    records: Vec<Record>,
+}
+
+impl Journal {
+    fn read(path: PathBuf) -> Result<Self> {
+        let mut lines = BufReader::new(fs::File::open(&path)?).lines();
+        let header = lines.next().context("Empty journal")??;
+        let header = serde_json::from_str(&header)?;
+        let records = lines
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            path,
+            header,
+            records,
+        })
+    }
+
+    /// Every session that has not been undone yet, from the newest one
+    fn list() -> Result<Vec<Self>> {
+        let dir = journal_dir()?;
+
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut paths = fs::read_dir(dir)?
+            .filter_map(Result::ok)
+            .map(|v| v.path())
+            .filter(|v| v.extension().filter(|&v| v == JOURNAL_EXT).is_some())
+            .collect::<Vec<_>>();
+
+        paths.sort_unstable_by(|a, b| b.cmp(a));
+
+        let journals = paths
+            .into_iter()
+            .filter_map(|path| match Self::read(path.to_owned()) {
+                Ok(v) => Some(v),
+                Err(why) => {
+                    log::warn!("Cannot read journal {:?}\n{:#?}", path, why);
+                    None
+                }
+            })
+            .collect();
+
+        Ok(journals)
+    }
+
+    /// Refuse when anything touched by the session changed since, then revert it in reverse.
+    ///
+    /// Records whose path is touched again later in the same session are only checked right
+    /// before they are reverted, after the later ones put their path back.
+    fn undo(self, dry_run: bool) -> Result<()> {
+        let id = &self.header.session;
+
+        let changed = self
+            .records
+            .iter()
+            .enumerate()
+            .filter(|(i, record)| !self.records[i + 1..].iter().any(|v| v.overlaps(record)))
+            .filter_map(|(_, record)| record.check().err())
+            .map(|why| why.to_string())
+            .collect::<Vec<_>>();
+
+        if !changed.is_empty() {
+            bail!(
+                "Refusing to undo session {id}, files changed since:\n{}",
+                changed.join("\n")
+            );
+        }
+
+        log::info!(
+            "Undoing session {id} ({}, {} operations)",
+            self.header.tool,
+            self.records.len()
+        );
+
+        for record in self.records.iter().rev() {
+            if dry_run {
+                println!("{record}");
+                continue;
+            }
+
+            record
+                .check()
+                .and_then(|_| Ok(record.revert()?))
+                .with_context(|| format!("Cannot {record}, session {id} is partially undone"))?;
+
+            log::info!("{record}");
+        }
+
+        if !dry_run {
+            fs::rename(&self.path, self.path.with_extension(UNDONE_EXT))?;
+        }
+
+        Ok(())
+    }
+
+    /// Permanently delete the trashed files of this session and forget about it
+    fn purge(self) -> Result<()> {
+        for trash in self.records.iter().filter_map(Record::trash) {
+            let res = match trash.is_dir() {
+                true => fs::remove_dir_all(trash),
+                false => fs::remove_file(trash),
+            };
+
+            if let Err(why) = res {
+                log::warn!("Cannot remove {:?}\n{:#?}", trash, why);
+                continue;
+            }
+
+            // the session directory and the trash directory, whenever they become empty
+            for dir in trash.ancestors().skip(1).take(2) {
+                fs::remove_dir(dir).ok();
+            }
+        }
+
+        fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Parser)]
+/// Revert the filesystem changes made by previous runs of the tools
+pub struct Undo {
+    #[arg(long, default_value_t = 1, conflicts_with = "session")]
+    /// Undo the N most recent sessions
+    last: usize,
+
+    #[arg(long)]
+    /// Undo a specific session
+    session: Option<String>,
+
+    #[arg(long, conflicts_with = "purge")]
+    /// List the sessions that can be undone
+    list: bool,
+
+    #[arg(long)]
+    /// Permanently delete every trashed file and forget all sessions
+    purge: bool,
+}
+
+impl Undo {
+    pub fn exec(&self) -> Result<()> {
+        let journals = Journal::list()?;
+
+        if self.list {
+            for journal in journals {
+                println!(
+                    "{}  {:<20} {} operations  {}",
+                    journal.header.session,
+                    journal.header.tool,
+                    journal.records.len(),
+                    journal.header.args.join(" ")
+                );
+            }
+
+            return Ok(());
+        }
+
+        if self.purge {
+            return journals.into_iter().try_for_each(Journal::purge);
+        }
+
+        let selected = match self.session {
+            Some(ref id) => {
+                let journal = journals
+                    .into_iter()
+                    .find(|v| &v.header.session == id)
+                    .with_context(|| format!("No session {id} to undo"))?;
+                vec![journal]
+            }
+            None => journals.into_iter().take(self.last).collect(),
+        };
+
+        if selected.is_empty() {
+            log::info!("Nothing to undo");
+        }
+
+        let dry_run = crate::global().dry_run;
+        selected.into_iter().try_for_each(|v| v.undo(dry_run))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    thread_local! {
+        /// Data directory of the test running on this thread
+        pub(super) static DATA_DIR: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+    }
+
+    /// A session whose journal and trash live in a temporary directory, with `files` next to it
+    fn session() -> (tempfile::TempDir, PathBuf, Session) {
+        let dir = tempfile::tempdir().unwrap();
+        let files = dir.path().join("files");
+
+        fs::create_dir(&files).unwrap();
+        DATA_DIR.with(|v| *v.borrow_mut() = Some(dir.path().join("data")));
+
+        let session = Session::open().unwrap();
+        (dir, files, session)
+    }
+
+    fn apply(session: &mut Session, op: Op) {
+        let record = session.apply(&op).unwrap().unwrap();
+        session.append(&record).unwrap();
+    }
+
+    fn undo() -> Result<()> {
+        let mut journals = Journal::list().unwrap();
+        assert_eq!(journals.len(), 1);
+        journals.remove(0).undo(false)
+    }
+
+    #[test]
+    fn undo_move() {
+        let (_dir, files, mut session) = session();
+        let (a, b) = (files.join("a"), files.join("b"));
+        fs::write(&a, "a").unwrap();
+        fs::write(&b, "b").unwrap();
+
+        apply(
+            &mut session,
+            Op::Move {
+                from: a.to_owned(),
+                to: b.to_owned(),
+            },
+        );
+
+        assert!(!a.exists());
+        assert_eq!(fs::read_to_string(&b).unwrap(), "a");
+
+        undo().unwrap();
+
+        assert_eq!(fs::read_to_string(&a).unwrap(), "a");
+        assert_eq!(fs::read_to_string(&b).unwrap(), "b");
+        assert!(Journal::list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn undo_delete() {
+        let (_dir, files, mut session) = session();
+        let a = files.join("a");
+        fs::create_dir(&a).unwrap();
+        fs::write(a.join("b"), "b").unwrap();
+
+        apply(&mut session, Op::Delete { path: a.to_owned() });
+        assert!(!a.exists());
+
+        undo().unwrap();
+        assert_eq!(fs::read_to_string(a.join("b")).unwrap(), "b");
+    }
+
+    #[test]
+    fn changed() {
+        let (_dir, files, mut session) = session();
+        let a = files.join("a");
+
+        apply(
+            &mut session,
+            Op::Write {
+                path: a.to_owned(),
+                contents: b"a".to_vec(),
+            },
+        );
+
+        fs::write(&a, "changed").unwrap();
+
+        let err = undo().unwrap_err();
+        assert!(err.to_string().contains("has changed"), "{err}");
+        assert_eq!(fs::read_to_string(&a).unwrap(), "changed");
+        assert_eq!(Journal::list().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn untrashable() {
+        let (dir, files, mut session) = session();
+        let a = files.join("a");
+        fs::write(&a, "a").unwrap();
+        fs::write(dir.path().join("data/trash"), "").unwrap();
+
+        assert!(session.apply(&Op::Delete { path: a.to_owned() }).is_err());
+        assert_eq!(fs::read_to_string(&a).unwrap(), "a");
+    }
+}
@@ -1,17 +1,255 @@
+pub mod cancel;
+pub mod config;
+pub mod generate;
+pub mod journal;
+pub mod plan;
+pub mod prompt;
+pub mod report;
+pub mod watch;
+
+use anyhow::Result;
+use clap::{ArgMatches, Args, Command, CommandFactory, FromArgMatches, ValueEnum};
+use config::Config;
+use std::env;
+use std::ffi::OsString;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Name of the multi-call binary that bundles every tool as a subcommand
+pub const MULTICALL_NAME: &str = "tmoutils";
+
+/// Generate the `main` function of a tool.
+///
+/// With a single crate it produces the standalone binary of that tool, with a list of crates it
+/// produces the multi-call binary where each tool is a subcommand named after its crate. The
+/// multi-call binary also dispatches on `argv[0]`, so it can be symlinked under the old names.
 #[macro_export]
 macro_rules! lib_main {
-    ($x:tt) => {
-        use clap::Parser;
-        use std::env;
+    ($x:ident) => {
+        fn main() -> anyhow::Result<()> {
+            let config = $crate::config::Config::load();
+            let command = config.apply($crate::command::<$x::Args>(stringify!($x)));
+            let matches = command.get_matches();
+            $crate::run(stringify!($x), &config, &matches, |args: $x::Args| args.exec())
+        }
+    };
 
+    ($($x:ident),+ $(,)?) => {
         fn main() -> anyhow::Result<()> {
-            if env::var("RUST_LOG").is_err() {
-                env::set_var("RUST_LOG", "info");
-            }
+            let config = $crate::config::Config::load();
+            let command = $crate::multicall_command(vec![
+                $crate::command::<$crate::journal::Undo>("undo"),
+                $(config.apply($crate::command::<$x::Args>(stringify!($x))),)+
+            ]);
 
-            pretty_env_logger::init();
+            let args = $crate::multicall_args(&command);
+            let matches = command.clone().get_matches_from(args);
 
-            $x::Args::parse().exec()
+            if let Some(res) = $crate::generate::handle(&command, &matches) {
+                return res;
+            }
+
+            match matches.subcommand() {
+                Some(("undo", matches)) => {
+                    $crate::run("undo", &config, matches, |args: $crate::journal::Undo| args.exec())
+                }
+                $(Some((stringify!($x), matches)) => {
+                    $crate::run(stringify!($x), &config, matches, |args: $x::Args| args.exec())
+                })+
+                _ => unreachable!("clap requires a subcommand"),
+            }
         }
     };
 }
+
+static GLOBAL: OnceLock<GlobalArgs> = OnceLock::new();
+static TOOL_NAME: OnceLock<String> = OnceLock::new();
+
+// Flags shared by every tool, added next to the tool's own `Args`. Not a doc comment, clap would
+// show it as the description of every tool.
+#[derive(Debug, Default, Clone, Args)]
+pub struct GlobalArgs {
+    #[arg(long)]
+    /// Show what would be done without touching the filesystem
+    pub dry_run: bool,
+
+    #[arg(long)]
+    /// Print the planned filesystem operations as JSON
+    pub plan_json: bool,
+
+    #[arg(long, conflicts_with = "no")]
+    /// Answer yes to every question instead of asking
+    pub yes: bool,
+
+    #[arg(long)]
+    /// Answer no to every question instead of asking
+    pub no: bool,
+
+    #[arg(long)]
+    /// Delete files for good instead of moving them into the trash, `tmoutils undo` cannot bring
+    /// them back then
+    pub no_trash: bool,
+
+    #[arg(long, value_name = "DAYS")]
+    /// Purge the trash of the sessions older than this, 7 days by default
+    pub trash_days: Option<u64>,
+
+    #[arg(long, value_name = "GIB")]
+    /// Keep at most this many GiB in the trash, the oldest sessions are purged first. Deleting or
+    /// replacing more than that in a single run fails unless `--no-trash` is set. 10 GiB by
+    /// default
+    pub trash_size: Option<u64>,
+
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    /// How to write the log, `json` also prints an event for every processed item to stdout
+    pub log_format: LogFormat,
+
+    #[arg(long)]
+    /// Show the effective value of every option and where it comes from, then exit
+    pub print_config: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// The shared flags of the current run, all off when not started through `lib_main!`
+pub fn global() -> &'static GlobalArgs {
+    GLOBAL.get_or_init(GlobalArgs::default)
+}
+
+/// The clap command of a tool, named after its crate instead of whatever its `Args` says.
+///
+/// Also holds the hidden `completions` and `man` subcommands.
+pub fn command<T: CommandFactory>(name: &str) -> Command {
+    GlobalArgs::augment_args(T::command().name(name.to_owned()).bin_name(name.to_owned()))
+        .subcommands(generate::subcommands())
+        .subcommand_negates_reqs(true)
+}
+
+/// Name of the running tool, empty when not started through `lib_main!`
+pub fn tool_name() -> &'static str {
+    TOOL_NAME.get().map(String::as_str).unwrap_or_default()
+}
+
+/// Run a tool out of its parsed matches, after setting up everything shared between the tools
+pub fn run<T>(
+    name: &str,
+    config: &Config,
+    matches: &ArgMatches,
+    exec: impl Fn(T) -> Result<()>,
+) -> Result<()>
+where
+    T: CommandFactory + FromArgMatches,
+{
+    if let Some(res) = generate::handle(&command::<T>(name), matches) {
+        return res;
+    }
+
+    init(name, matches);
+
+    let command = GlobalArgs::augment_args(T::command());
+    config.check(name, &command);
+
+    if global().print_config {
+        config.print(name, &command, matches);
+        return Ok(());
+    }
+
+    if matches.try_get_one::<bool>("watch").ok().flatten() == Some(&true) {
+        let dir = input_dir(matches);
+        return report::finish(watch::watch(&dir, || exec(from_matches(matches))));
+    }
+
+    report::finish(exec(from_matches(matches)))
+}
+
+/// The directory a tool works in, its `location` or `path` argument or the current directory
+fn input_dir(matches: &ArgMatches) -> PathBuf {
+    ["location", "path"]
+        .into_iter()
+        .filter(|&id| matches.try_contains_id(id).unwrap_or(false))
+        .find_map(|id| matches.get_raw(id)?.next())
+        .map(PathBuf::from)
+        .filter(|v| v.is_dir())
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+/// Store the shared flags and set up logging
+pub fn init(name: &str, matches: &ArgMatches) {
+    TOOL_NAME.set(name.to_owned()).ok();
+    GLOBAL.set(from_matches(matches)).ok();
+    init_logger();
+    cancel::install();
+}
+
+/// The multi-call command that holds every tool as a subcommand
+pub fn multicall_command(tools: Vec<Command>) -> Command {
+    Command::new(MULTICALL_NAME)
+        .about("Every tmoutils tool in a single binary")
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .subcommands(tools)
+        .subcommands(generate::subcommands())
+}
+
+/// Command line arguments for the multi-call binary.
+///
+/// When invoked through a symlink named after a tool (e.g. `to_720p`), the arguments are
+/// rewritten into `tmoutils to_720p ...` so the old scripts keep working.
+pub fn multicall_args(command: &Command) -> Vec<OsString> {
+    let mut args = env::args_os().collect::<Vec<_>>();
+
+    let invoked = args
+        .first()
+        .and_then(|v| Path::new(v).file_stem())
+        .and_then(|v| v.to_str())
+        .map(String::from);
+
+    if let Some(name) = invoked.filter(|v| command.find_subcommand(v).is_some()) {
+        args[0] = name.into();
+        args.insert(0, MULTICALL_NAME.into());
+    }
+
+    args
+}
+
+/// Build the tool arguments out of the parsed matches, exit with clap's message on failure
+pub fn from_matches<T: FromArgMatches>(matches: &ArgMatches) -> T {
+    T::from_arg_matches(matches).unwrap_or_else(|why| why.exit())
+}
+
+pub fn init_logger() {
+    if env::var("RUST_LOG").is_err() {
+        env::set_var("RUST_LOG", "info");
+    }
+
+    if global().log_format == LogFormat::Text {
+        pretty_env_logger::init();
+        return;
+    }
+
+    let mut builder = pretty_env_logger::formatted_builder();
+
+    if let Ok(filters) = env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+
+    builder.format(|buf, record| {
+        let line = serde_json::json!({
+            "event": "log",
+            "level": record.level().as_str(),
+            "target": record.target(),
+            "message": record.args().to_string(),
+        });
+
+        writeln!(buf, "{line}")
+    });
+
+    builder.init();
+}
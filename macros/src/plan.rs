@@ -0,0 +1,324 @@
+use crate::report::Item;
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// A single filesystem mutation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Op {
+    Move {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    Copy {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// Delete a file, or a directory with everything inside it
+    Delete {
+        path: PathBuf,
+    },
+    /// Create a directory and all of its missing parents
+    Mkdir {
+        path: PathBuf,
+    },
+    Write {
+        path: PathBuf,
+        #[serde(rename = "bytes", serialize_with = "serialize_len")]
+        contents: Vec<u8>,
+    },
+}
+
+impl Op {
+    pub fn apply(&self) -> io::Result<()> {
+        match self {
+            Self::Move { from, to } => move_path(from, to),
+            Self::Copy { from, to } => fs::copy(from, to).map(|_| ()),
+            Self::Delete { path } if path.is_dir() => fs::remove_dir_all(path),
+            Self::Delete { path } => fs::remove_file(path),
+            Self::Mkdir { path } => fs::create_dir_all(path),
+            Self::Write { path, contents } => fs::write(path, contents),
+        }
+    }
+}
+
+impl Op {
+    /// The path created by the operation, if any
+    pub fn target(&self) -> Option<&Path> {
+        match self {
+            Self::Move { to, .. } | Self::Copy { to, .. } => Some(to),
+            Self::Mkdir { path } | Self::Write { path, .. } => Some(path),
+            Self::Delete { .. } => None,
+        }
+    }
+
+    fn item(&self) -> Item {
+        match self {
+            Self::Move { from, to } => Item::new("move", from).output(to),
+            Self::Copy { from, to } => Item::new("copy", from).output(to),
+            Self::Delete { path } => Item::new("delete", path),
+            Self::Mkdir { path } => Item::new("mkdir", path).output(path),
+            Self::Write { path, .. } => Item::new("write", path).output(path),
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Move { from, to } => write!(f, "move {:?} => {:?}", from, to),
+            Self::Copy { from, to } => write!(f, "copy {:?} => {:?}", from, to),
+            Self::Delete { path } => write!(f, "delete {:?}", path),
+            Self::Mkdir { path } => write!(f, "mkdir {:?}", path),
+            Self::Write { path, contents } => {
+                write!(f, "write {:?} ({} bytes)", path, contents.len())
+            }
+        }
+    }
+}
+
+/// List of filesystem mutations a tool wants to make.
+///
+/// Tools collect everything they are going to do into a plan first, then hand it to
+/// [`Plan::execute`], which takes care of `--dry-run`, `--plan-json` and error reporting.
+#[derive(Debug, Default, Serialize)]
+#[serde(transparent)]
+pub struct Plan {
+    ops: Vec<Op>,
+}
+
+impl Plan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue an operation, does nothing if the very same operation is already queued
+    pub fn push(&mut self, op: Op) {
+        if !self.ops.contains(&op) {
+            self.ops.push(op);
+        }
+    }
+
+    pub fn rename(&mut self, from: impl Into<PathBuf>, to: impl Into<PathBuf>) {
+        self.push(Op::Move {
+            from: from.into(),
+            to: to.into(),
+        });
+    }
+
+    pub fn copy(&mut self, from: impl Into<PathBuf>, to: impl Into<PathBuf>) {
+        self.push(Op::Copy {
+            from: from.into(),
+            to: to.into(),
+        });
+    }
+
+    pub fn delete(&mut self, path: impl Into<PathBuf>) {
+        self.push(Op::Delete { path: path.into() });
+    }
+
+    pub fn mkdir(&mut self, path: impl Into<PathBuf>) {
+        self.push(Op::Mkdir { path: path.into() });
+    }
+
+    pub fn write(&mut self, path: impl Into<PathBuf>, contents: impl Into<Vec<u8>>) {
+        self.push(Op::Write {
+            path: path.into(),
+            contents: contents.into(),
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Op> {
+        self.ops.iter()
+    }
+
+    /// Print the plan in a human readable form
+    pub fn preview(&self) {
+        for (i, op) in self.ops.iter().enumerate() {
+            println!("#{i} {op}");
+        }
+    }
+
+    /// Apply every operation in order, recording them into the undo journal.
+    ///
+    /// A failed operation is logged and does not stop the remaining ones, the returned error only
+    /// tells how many of them failed. With `--plan-json` the plan is dumped to stdout first, with
+    /// `--dry-run` nothing is applied. The operations are only logged, the tool reports the items
+    /// they belong to.
+    pub fn execute(self) -> anyhow::Result<()> {
+        self.apply(false)
+    }
+
+    /// Same as [`Plan::execute`], for tools whose items are the operations themselves, every
+    /// operation is reported as an item of the run
+    pub fn execute_reported(self) -> anyhow::Result<()> {
+        self.apply(true)
+    }
+
+    fn apply(self, report: bool) -> anyhow::Result<()> {
+        let global = crate::global();
+
+        if global.plan_json {
+            println!("{}", serde_json::to_string_pretty(&self)?);
+        }
+
+        if global.dry_run {
+            if !global.plan_json {
+                self.preview();
+            }
+
+            return Ok(());
+        }
+
+        let mut failed = 0;
+
+        for op in &self.ops {
+            match report {
+                true => log::info!("{op}"),
+                false => log::debug!("{op}"),
+            }
+
+            let item = report.then(|| op.item());
+            let res = crate::journal::apply(op);
+
+            match res {
+                Ok(()) => {
+                    if let Some(target) = op.target() {
+                        crate::watch::produced(target);
+                    }
+                }
+                Err(ref why) => {
+                    log::error!("Cannot {op}\n{:#?}", why);
+                    failed += 1;
+                }
+            }
+
+            if let Some(item) = item {
+                item.result(&res);
+            }
+        }
+
+        if failed > 0 {
+            anyhow::bail!("{failed} of {} operations failed", self.ops.len());
+        }
+
+        Ok(())
+    }
+}
+
+impl IntoIterator for Plan {
+    type Item = Op;
+    type IntoIter = std::vec::IntoIter<Op>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.into_iter()
+    }
+}
+
+/// Rename, falling back to copy and delete when the target is on another filesystem
+fn move_path(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(why) if why.kind() == ErrorKind::CrossesDevices && from.is_file() => {
+            fs::copy(from, to)?;
+            fs::remove_file(from)
+        }
+        res => res,
+    }
+}
+
+fn serialize_len<T, S>(contents: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: AsRef<[u8]>,
+    S: Serializer,
+{
+    serializer.serialize_u64(contents.as_ref().len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dedup() {
+        let mut plan = Plan::new();
+        plan.rename("a", "b");
+        plan.rename("a", "b");
+        plan.rename("a", "c");
+        plan.delete("a");
+
+        assert_eq!(plan.len(), 3);
+    }
+
+    #[test]
+    fn json() {
+        let mut plan = Plan::new();
+        plan.rename("a", "b");
+        plan.write("c", "hello");
+
+        assert_eq!(
+            serde_json::to_string(&plan).unwrap(),
+            r#"[{"op":"move","from":"a","to":"b"},{"op":"write","path":"c","bytes":5}]"#
+        );
+    }
+
+    #[test]
+    fn target() {
+        let mut plan = Plan::new();
+        plan.rename("a", "b");
+        plan.copy("a", "c");
+        plan.delete("a");
+        plan.mkdir("d");
+        plan.write("e", "");
+
+        let targets = plan.iter().map(Op::target).collect::<Vec<_>>();
+        let expected = [Some("b"), Some("c"), None, Some("d"), Some("e")];
+
+        assert_eq!(targets, expected.map(|v| v.map(Path::new)));
+    }
+
+    #[test]
+    fn apply() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+
+        let ops = [
+            Op::Mkdir { path: path("d/e") },
+            Op::Write {
+                path: path("d/a"),
+                contents: b"hello".to_vec(),
+            },
+            Op::Copy {
+                from: path("d/a"),
+                to: path("d/e/b"),
+            },
+            Op::Move {
+                from: path("d/a"),
+                to: path("c"),
+            },
+        ];
+
+        for op in &ops {
+            op.apply().unwrap();
+        }
+
+        assert!(!path("d/a").exists());
+        assert_eq!(fs::read_to_string(path("c")).unwrap(), "hello");
+        assert_eq!(fs::read_to_string(path("d/e/b")).unwrap(), "hello");
+
+        Op::Delete { path: path("d") }.apply().unwrap();
+        Op::Delete { path: path("c") }.apply().unwrap();
+
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,141 @@
+use anyhow::{bail, Result};
+use std::io::{self, BufRead as _, IsTerminal as _, Write as _};
+
+/// Confirmation asked for a batch of items, one question per item.
+///
+/// On top of yes and no the user can answer `all` to accept the current and every following
+/// item, or `quit` to refuse them all. With `--yes`, `--no` or `--dry-run` nothing is asked at
+/// all, and without a terminal on stdin asking is an error rather than a silent no.
+#[derive(Debug, Default)]
+pub struct Prompt {
+    all: bool,
+    quit: bool,
+}
+
+impl Prompt {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn ask(&mut self, question: &str) -> Result<bool> {
+        if self.all || self.quit {
+            return Ok(self.all);
+        }
+
+        if let Some(answer) = preset(question) {
+            return Ok(answer);
+        }
+
+        loop {
+            let line = read_line(question, "[y]es/[N]o/[a]ll/[q]uit")?;
+
+            if let Some(answer) = self.answer(line.as_deref()) {
+                return Ok(answer);
+            }
+        }
+    }
+
+    /// Take in an answer read from the user, `None` when it is not one of the choices
+    fn answer(&mut self, line: Option<&str>) -> Option<bool> {
+        match line {
+            Some("y" | "yes") => Some(true),
+            Some("" | "n" | "no") => Some(false),
+            Some("a" | "all") => {
+                self.all = true;
+                Some(true)
+            }
+            Some("q" | "quit") | None => {
+                self.quit = true;
+                Some(false)
+            }
+            Some(_) => None,
+        }
+    }
+
+    /// The user answered `quit`, every following question is answered no
+    pub fn is_quit(&self) -> bool {
+        self.quit
+    }
+}
+
+/// Fail early when questions are going to be asked but nobody can answer them
+pub fn check() -> Result<()> {
+    let global = crate::global();
+
+    if !global.yes && !global.no && !global.dry_run && !io::stdin().is_terminal() {
+        bail!("stdin is not a terminal, use --yes or --no to answer the questions");
+    }
+
+    Ok(())
+}
+
+/// The answer given on the command line with `--yes` or `--no`, a dry run goes on as if it
+/// was yes since nothing is applied anyway
+fn preset(question: &str) -> Option<bool> {
+    let global = crate::global();
+    let answer = global
+        .yes
+        .then_some(true)
+        .or(global.no.then_some(false))
+        .or(global.dry_run.then_some(true))?;
+
+    log::info!("{question} {}", if answer { "yes" } else { "no" });
+
+    Some(answer)
+}
+
+/// Read the lowercased answer, `None` on end of input
+fn read_line(question: &str, choices: &str) -> Result<Option<String>> {
+    if !io::stdin().is_terminal() {
+        bail!("Cannot ask \"{question}\", stdin is not a terminal. Use --yes or --no");
+    }
+
+    print!("{question} ({choices}): ");
+    io::stdout().flush()?;
+
+    let mut line = String::new();
+
+    if io::stdin().lock().read_line(&mut line)? == 0 {
+        println!();
+        return Ok(None);
+    }
+
+    Ok(Some(line.trim().to_ascii_lowercase()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers() {
+        let mut prompt = Prompt::new();
+
+        assert_eq!(prompt.answer(Some("y")), Some(true));
+        assert_eq!(prompt.answer(Some("yes")), Some(true));
+        assert_eq!(prompt.answer(Some("")), Some(false));
+        assert_eq!(prompt.answer(Some("no")), Some(false));
+        assert_eq!(prompt.answer(Some("maybe")), None);
+        assert!(!prompt.all && !prompt.is_quit());
+    }
+
+    #[test]
+    fn all() {
+        let mut prompt = Prompt::new();
+
+        assert_eq!(prompt.answer(Some("a")), Some(true));
+        assert!(prompt.ask("Again?").unwrap());
+        assert!(!prompt.is_quit());
+    }
+
+    #[test]
+    fn quit() {
+        for line in [Some("q"), Some("quit"), None] {
+            let mut prompt = Prompt::new();
+
+            assert_eq!(prompt.answer(line), Some(false));
+            assert!(!prompt.ask("Again?").unwrap());
+            assert!(prompt.is_quit());
+        }
+    }
+}
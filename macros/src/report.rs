@@ -0,0 +1,279 @@
+use crate::LogFormat;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+
+static SUMMARY: Mutex<Summary> = Mutex::new(Summary {
+    succeeded: 0,
+    skipped: 0,
+    failed: 0,
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Succeeded,
+    Skipped,
+    Failed,
+}
+
+/// Counts of the items processed in this run
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct Summary {
+    pub succeeded: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+impl Summary {
+    pub fn total(&self) -> usize {
+        self.succeeded + self.skipped + self.failed
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} succeeded, {} skipped, {} failed",
+            self.succeeded, self.skipped, self.failed
+        )
+    }
+}
+
+/// A single thing a tool works on, e.g. a video to encode or a file to move.
+///
+/// The item is timed from its creation, and finishing it counts it into the summary of the run.
+/// With `--log-format json` every finished item is also printed to stdout as a JSON event.
+#[derive(Debug)]
+#[must_use = "an item is only reported once it is finished"]
+pub struct Item {
+    action: String,
+    input: PathBuf,
+    output: Option<PathBuf>,
+    bytes_before: Option<u64>,
+    metrics: BTreeMap<String, f64>,
+    start: Instant,
+}
+
+#[derive(Serialize)]
+struct Event<'a> {
+    event: &'static str,
+    tool: &'a str,
+    action: &'a str,
+    status: Status,
+    input: &'a Path,
+    output: Option<&'a Path>,
+    bytes_before: Option<u64>,
+    bytes_after: Option<u64>,
+    /// In seconds
+    duration: f64,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    metrics: &'a BTreeMap<String, f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Item {
+    pub fn new(action: impl Into<String>, input: impl Into<PathBuf>) -> Self {
+        let input = input.into();
+
+        Self {
+            action: action.into(),
+            bytes_before: file_size(&input),
+            input,
+            output: None,
+            metrics: BTreeMap::new(),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn output(mut self, output: impl Into<PathBuf>) -> Self {
+        self.output = Some(output.into());
+        self
+    }
+
+    /// Set where the result ended up once it is known
+    pub fn set_output(&mut self, output: impl Into<PathBuf>) {
+        self.output = Some(output.into());
+    }
+
+    /// Attach a measure of the result, e.g. a quality score, reported with the item
+    pub fn set_metric(&mut self, name: impl Into<String>, value: f64) {
+        self.metrics.insert(name.into(), value);
+    }
+
+    pub fn succeeded(self) {
+        self.finish(Status::Succeeded, None);
+    }
+
+    pub fn skipped(self, reason: impl fmt::Display) {
+        self.finish(Status::Skipped, Some(reason.to_string()));
+    }
+
+    pub fn failed(self, why: impl fmt::Display) {
+        self.finish(Status::Failed, Some(format!("{why:#}")));
+    }
+
+    /// Succeeded or failed depending on the result
+    pub fn result<T, E: fmt::Display>(self, res: &Result<T, E>) {
+        match res {
+            Ok(_) => self.succeeded(),
+            Err(why) => self.failed(why),
+        }
+    }
+
+    fn finish(self, status: Status, message: Option<String>) {
+        let mut summary = SUMMARY.lock().unwrap();
+
+        match status {
+            Status::Succeeded => summary.succeeded += 1,
+            Status::Skipped => summary.skipped += 1,
+            Status::Failed => summary.failed += 1,
+        }
+
+        drop(summary);
+
+        let bytes_after = match status {
+            Status::Succeeded => self.output.as_deref().and_then(file_size),
+            _ => None,
+        };
+
+        log::debug!("{} {:?}: {:?}", self.action, self.input, status);
+
+        if crate::global().log_format != LogFormat::Json {
+            return;
+        }
+
+        let (reason, error) = match status {
+            Status::Skipped => (message, None),
+            _ => (None, message),
+        };
+
+        let event = Event {
+            event: "item",
+            tool: crate::tool_name(),
+            action: &self.action,
+            status,
+            input: &self.input,
+            output: self.output.as_deref(),
+            bytes_before: self.bytes_before,
+            bytes_after,
+            duration: self.start.elapsed().as_secs_f64(),
+            metrics: &self.metrics,
+            reason,
+            error,
+        };
+
+        if let Ok(line) = serde_json::to_string(&event) {
+            println!("{line}");
+        }
+    }
+}
+
+/// The counts of the items reported so far
+pub fn summary() -> Summary {
+    *SUMMARY.lock().unwrap()
+}
+
+/// Print the summary of the run and turn failed items into a failed run
+pub fn finish(res: anyhow::Result<()>) -> anyhow::Result<()> {
+    let summary = summary();
+
+    if summary.total() == 0 {
+        return res;
+    }
+
+    match crate::global().log_format {
+        LogFormat::Text => log::info!("{summary}"),
+        LogFormat::Json => {
+            #[derive(Serialize)]
+            struct Event<'a> {
+                event: &'static str,
+                tool: &'a str,
+                #[serde(flatten)]
+                summary: Summary,
+            }
+
+            let event = Event {
+                event: "summary",
+                tool: crate::tool_name(),
+                summary,
+            };
+
+            if let Ok(line) = serde_json::to_string(&event) {
+                println!("{line}");
+            }
+        }
+    }
+
+    res?;
+
+    if summary.failed > 0 {
+        anyhow::bail!("{} of {} items failed", summary.failed, summary.total());
+    }
+
+    Ok(())
+}
+
+fn file_size(path: &Path) -> Option<u64> {
+    fs::metadata(path)
+        .ok()
+        .filter(|v| v.is_file())
+        .map(|v| v.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        let summary = Summary {
+            succeeded: 3,
+            skipped: 2,
+            failed: 1,
+        };
+
+        assert_eq!(summary.total(), 6);
+        assert_eq!(summary.to_string(), "3 succeeded, 2 skipped, 1 failed");
+    }
+
+    #[test]
+    fn size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        fs::write(&path, "hello").unwrap();
+
+        assert_eq!(file_size(&path), Some(5));
+        assert_eq!(file_size(dir.path()), None);
+        assert_eq!(file_size(&dir.path().join("b")), None);
+    }
+
+    // The only test finishing items, the summary is shared by the whole process
+    #[test]
+    fn counted() {
+        let before = summary();
+
+        Item::new("test", "a").succeeded();
+        Item::new("test", "b").result(&Ok::<_, String>(()));
+        Item::new("test", "c").skipped("Nothing to do");
+
+        let after = summary();
+        assert_eq!(after.succeeded - before.succeeded, 2);
+        assert_eq!(after.skipped - before.skipped, 1);
+        assert_eq!(after.failed, before.failed);
+        assert!(finish(Ok(())).is_ok());
+
+        Item::new("test", "d").result(&Err::<(), _>("broken"));
+
+        assert_eq!(summary().failed - before.failed, 1);
+        assert!(finish(Ok(())).is_err());
+    }
+}
@@ -0,0 +1,266 @@
+use crate::cancel;
+use crate::journal::TRASH_DIR;
+use anyhow::{Context as _, Result};
+use clap::Args;
+use notify::{EventKind, RecursiveMode, Watcher as _};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a file must stay the same size before it is handed to the tool
+const SETTLE_TIME: Duration = Duration::from_secs(3);
+
+/// How often the pending files are checked
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The paths of the current `--watch` run, `None` when not watching
+static BATCH: Mutex<Option<Vec<PathBuf>>> = Mutex::new(None);
+
+/// Results of the tool itself, they are not new inputs. Each one is kept until its events have
+/// been quiet for `SETTLE_TIME`
+static PRODUCED: Mutex<BTreeMap<PathBuf, Instant>> = Mutex::new(BTreeMap::new());
+
+// The `--watch` flag, flattened into the `Args` of the tools that filter their inputs with
+// `includes`. Not a doc comment, clap would show it as the description of the tool.
+#[derive(Debug, Default, Clone, Args)]
+pub struct WatchArgs {
+    #[arg(long)]
+    /// Keep running, and process the new files in the input directory once they stop growing
+    pub watch: bool,
+}
+
+/// Whether the tool should work on this path in the current run.
+///
+/// Always true without `--watch`. While watching, only the paths that have just settled, or the
+/// paths inside a settled directory, are included.
+pub fn includes(path: &Path) -> bool {
+    let batch = BATCH.lock().unwrap();
+
+    let Some(ref batch) = *batch else {
+        return true;
+    };
+
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_owned());
+    batch.iter().any(|v| path.starts_with(v))
+}
+
+/// Remember a path written by the tool so watching does not feed it back as a new input
+pub fn produced(path: &Path) {
+    if BATCH.lock().unwrap().is_none() {
+        return;
+    }
+
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_owned());
+    PRODUCED.lock().unwrap().insert(path, Instant::now());
+}
+
+/// A file that has been touched and is not yet considered finished
+struct Pending {
+    len: Option<u64>,
+    changed: Instant,
+}
+
+/// Watch `dir` for new or changed files and run the tool on them once they stop growing.
+///
+/// Runs until cancelled with Ctrl-C. A failed run is logged and the watching goes on.
+pub fn watch(dir: &Path, mut exec: impl FnMut() -> Result<()>) -> Result<()> {
+    let dir = dir
+        .canonicalize()
+        .with_context(|| format!("Cannot watch {:?}", dir))?;
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(&dir, RecursiveMode::Recursive)?;
+
+    log::info!("Watching {:?}, press Ctrl-C to stop", dir);
+
+    let mut pending = HashMap::<PathBuf, Pending>::new();
+    let mut checked = Instant::now();
+
+    while !cancel::is_cancelled() {
+        match rx.recv_timeout(POLL_INTERVAL) {
+            Ok(Ok(event)) => track(&mut pending, event),
+            Ok(Err(why)) => log::warn!("Watch error\n{:#?}", why),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+
+        if checked.elapsed() < POLL_INTERVAL {
+            continue;
+        }
+
+        checked = Instant::now();
+        prune_produced();
+
+        let settled = settle(&mut pending);
+
+        if settled.is_empty() {
+            continue;
+        }
+
+        log::info!("{} new paths settled", settled.len());
+
+        *BATCH.lock().unwrap() = Some(settled);
+
+        if let Err(why) = exec() {
+            log::error!("{:#}", why);
+        }
+
+        *BATCH.lock().unwrap() = Some(Vec::new());
+
+        // Their events may still be queued behind a long run
+        let now = Instant::now();
+        PRODUCED.lock().unwrap().values_mut().for_each(|v| *v = now);
+    }
+
+    Ok(())
+}
+
+fn track(pending: &mut HashMap<PathBuf, Pending>, event: notify::Event) {
+    if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+        return;
+    }
+
+    let mut produced = PRODUCED.lock().unwrap();
+
+    for path in event.paths {
+        if let Some(seen) = produced.get_mut(&path) {
+            *seen = Instant::now();
+            continue;
+        }
+
+        if path.components().any(|v| v.as_os_str() == TRASH_DIR) {
+            continue;
+        }
+
+        log::trace!("{:?} {:?}", event.kind, path);
+
+        let item = Pending {
+            len: None,
+            changed: Instant::now(),
+        };
+
+        pending.insert(path, item);
+    }
+}
+
+/// Forget the results of the tool whose events are over, so the path counts as an input again
+fn prune_produced() {
+    PRODUCED
+        .lock()
+        .unwrap()
+        .retain(|_, seen| seen.elapsed() < SETTLE_TIME);
+}
+
+/// Take the pending paths whose size has not changed for long enough
+fn settle(pending: &mut HashMap<PathBuf, Pending>) -> Vec<PathBuf> {
+    let mut settled = Vec::new();
+
+    pending.retain(|path, item| {
+        let Ok(metadata) = fs::metadata(path) else {
+            // Gone already, most likely a temporary file
+            return false;
+        };
+
+        let len = metadata.is_file().then_some(metadata.len());
+
+        if item.len != len {
+            item.len = len;
+            item.changed = Instant::now();
+            return true;
+        }
+
+        if item.changed.elapsed() < SETTLE_TIME {
+            return true;
+        }
+
+        settled.push(path.to_owned());
+        false
+    });
+
+    settled.sort();
+    settled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{CreateKind, RemoveKind};
+
+    fn event(kind: EventKind, paths: &[&Path]) -> notify::Event {
+        paths.iter().fold(notify::Event::new(kind), |event, v| {
+            event.add_path(v.to_path_buf())
+        })
+    }
+
+    #[test]
+    fn tracked() {
+        let dir = tempfile::tempdir().unwrap();
+        let (new, output) = (dir.path().join("new"), dir.path().join("output"));
+        let trashed = dir.path().join(TRASH_DIR).join("a");
+
+        PRODUCED
+            .lock()
+            .unwrap()
+            .insert(output.to_owned(), Instant::now());
+
+        let mut pending = HashMap::new();
+        let paths = [new.as_path(), &output, &trashed];
+        track(
+            &mut pending,
+            event(EventKind::Create(CreateKind::File), &paths),
+        );
+        track(
+            &mut pending,
+            event(EventKind::Remove(RemoveKind::File), &paths),
+        );
+
+        assert_eq!(pending.keys().collect::<Vec<_>>(), [&new]);
+    }
+
+    #[test]
+    fn pruned() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old, fresh) = (dir.path().join("old"), dir.path().join("fresh"));
+        let long_ago = Instant::now() - SETTLE_TIME * 2;
+
+        PRODUCED.lock().unwrap().insert(old.to_owned(), long_ago);
+        PRODUCED
+            .lock()
+            .unwrap()
+            .insert(fresh.to_owned(), Instant::now());
+        prune_produced();
+
+        let produced = PRODUCED.lock().unwrap();
+        assert!(!produced.contains_key(&old));
+        assert!(produced.contains_key(&fresh));
+    }
+
+    #[test]
+    fn settled() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        let long_ago = Instant::now() - SETTLE_TIME * 2;
+
+        fs::write(path("done"), "done").unwrap();
+        fs::write(path("growing"), "growing").unwrap();
+
+        let mut pending = HashMap::new();
+        let mut add = |name: &str, len: Option<u64>| {
+            let item = Pending {
+                len,
+                changed: long_ago,
+            };
+            pending.insert(path(name), item);
+        };
+
+        add("done", Some(4));
+        add("growing", Some(4));
+        add("gone", None);
+
+        assert_eq!(settle(&mut pending), [path("done")]);
+        assert_eq!(pending.keys().collect::<Vec<_>>(), [&path("growing")]);
+    }
+}
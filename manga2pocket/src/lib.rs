@@ -1,9 +1,13 @@
 use clap::*;
+use macros::cancel::{self, Cancelled, Partial};
+use macros::report::Item;
 use rayon::prelude::*;
 use serde::*;
 use std::fmt::Write as _;
 use std::fs;
 use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
 const ARCHIVE_TYPE: &[&str] = &["zip", "cbz"];
 const IMAGE_TYPE: &[&str] = &["jpg", "jpeg", "png", "gif"];
@@ -80,7 +84,7 @@ xmlns:xsd=\"http://www.w3.org/2001/XMLSchema\">
         res.push_str("<Genre>");
         res.push_str(&self.tags.join(","));
         if !self.tags.is_empty() && !self.parody.is_empty() {
-            res.push_str(",");
+            res.push(',');
         }
         res.push_str(&self.parody.join(","));
         res.push_str("</Genre>");
@@ -126,7 +130,8 @@ pub struct Args {
 
 impl Args {
     pub fn exec(&self) -> anyhow::Result<()> {
-        fs::read_dir(&self.location)?
+        let finished = Mutex::new(Vec::new());
+        let archives = fs::read_dir(&self.location)?
             .filter_map(Result::ok)
             .filter(|v| {
                 v.path()
@@ -134,21 +139,43 @@ impl Args {
                     .filter(|v| ARCHIVE_TYPE.iter().any(|x| x == v))
                     .is_some()
             })
-            .collect::<Vec<_>>()
-            .into_par_iter()
-            .for_each(|v| {
-                let path = v.path();
-                log::info!("{:?}", path);
-                if let Err(why) = process_archive(&path, self) {
+            .collect::<Vec<_>>();
+
+        archives.par_iter().for_each(|v| {
+            if cancel::is_cancelled() {
+                return;
+            }
+
+            let path = v.path();
+            log::info!("{:?}", path);
+
+            let item = Item::new("convert", &path);
+
+            match process_archive(&path, self) {
+                Ok(Some(output)) => {
+                    item.output(output).succeeded();
+                    finished.lock().unwrap().push(path);
+                }
+                Ok(None) => item.skipped("The output already exists"),
+                Err(_) if cancel::is_cancelled() => item.failed(Cancelled),
+                Err(why) => {
                     log::error!("{:?}: Cannot process the archive\n{:#?}", path, why);
+                    item.failed(why);
                 }
-            });
+            }
+        });
+
+        if cancel::is_cancelled() {
+            cancel::summary(&finished.into_inner().unwrap(), archives.len());
+            return Err(Cancelled.into());
+        }
 
         Ok(())
     }
 }
 
-fn process_archive(archive: &std::path::Path, opt: &Args) -> anyhow::Result<()> {
+/// Convert an archive, returns the path of the result or `None` when it is skipped
+fn process_archive(archive: &Path, opt: &Args) -> anyhow::Result<Option<PathBuf>> {
     let path = archive.canonicalize()?;
     let file = fs::File::open(&path)?;
     let mut zip = zip::ZipArchive::new(file)?;
@@ -160,21 +187,29 @@ fn process_archive(archive: &std::path::Path, opt: &Args) -> anyhow::Result<()>
     let mut pages: Vec<Page> = Vec::new();
     let Some(filestem) = path.file_stem() else {
         log::error!("{:?}: no filename", path);
-        return Ok(());
+        return Ok(None);
     };
 
     let filename = format!("{}.cbz", filestem.to_str().unwrap());
-    let mut new_path = std::path::PathBuf::from(&opt.output_dir);
+    let mut new_path = PathBuf::from(&opt.output_dir);
     new_path.push(filename);
 
     if !opt.overwrite && new_path.exists() {
-        return Ok(());
+        return Ok(None);
+    }
+
+    if macros::global().dry_run {
+        log::info!("convert {:?} => {:?}", path, new_path);
+        return Ok(Some(new_path));
     }
 
-    let new_file = fs::File::create(new_path)?;
+    let partial = Partial::replacing(new_path);
+    let new_file = fs::File::create(partial.path())?;
     let mut result = zip::ZipWriter::new(new_file);
 
     for (i, file) in inner_files.into_iter().enumerate() {
+        cancel::check()?;
+
         if METADATA_FILE.contains(&&*file) {
             let file = zip.by_name(&file)?;
             let mut thumbnail = 1;
@@ -187,10 +222,8 @@ fn process_archive(archive: &std::path::Path, opt: &Args) -> anyhow::Result<()>
                 Err(why) => log::error!("{:?}: cannot parse metadata\n{:#?}", path, why),
             }
 
-            if thumbnail != 1 {
-                if pages.len() >= thumbnail {
-                    pages[thumbnail - 1].is_cover = true;
-                }
+            if thumbnail != 1 && pages.len() >= thumbnail {
+                pages[thumbnail - 1].is_cover = true;
             }
 
             continue;
@@ -262,7 +295,9 @@ fn process_archive(archive: &std::path::Path, opt: &Args) -> anyhow::Result<()>
         log::warn!("No metadata found in {:?}", archive);
     }
 
-    Ok(())
+    result.finish()?;
+
+    Ok(Some(partial.finish()?))
 }
 
 fn default_page() -> u64 {
@@ -1,8 +1,11 @@
 use clap::*;
+use ffmpeg_wrapper::Ffmpeg;
+use macros::plan::Plan;
+use macros::prompt::{self, Prompt};
+use macros::report::Item;
+use std::fmt::Write as _;
 use std::fs;
-use std::io::{self, Write};
-use std::path::PathBuf;
-use std::process::Command;
+use std::path::{Path, PathBuf};
 
 const EXT: &[&str] = &["mp4", "avi", "mkv", "wmv"];
 
@@ -15,10 +18,30 @@ pub struct Args {
 
 impl Args {
     pub fn exec(&self) -> anyhow::Result<()> {
-        fs::read_dir(&self.location)?
+        prompt::check()?;
+
+        let mut merge = Prompt::new();
+        let mut delete = Prompt::new();
+
+        for (name, data) in fs::read_dir(&self.location)?
             .filter_map(|v| v.ok())
             .filter_map(get_dup)
-            .for_each(|(name, data)| write_ffmpeg_merge(&self.location, name, data).unwrap());
+        {
+            if merge.is_quit() {
+                break;
+            }
+
+            let item = Item::new("merge", &name).output(merged_path(&name));
+
+            match write_ffmpeg_merge(&self.location, &name, data, &mut merge, &mut delete) {
+                Ok(true) => item.succeeded(),
+                Ok(false) => item.skipped("Not confirmed"),
+                Err(why) => {
+                    log::error!("Cannot merge the videos in {:?}\n{:#}", name, why);
+                    item.failed(why);
+                }
+            }
+        }
 
         Ok(())
     }
@@ -51,14 +74,16 @@ fn get_dup(dir: fs::DirEntry) -> Option<(PathBuf, Vec<PathBuf>)> {
 
 fn write_ffmpeg_merge(
     location: impl Into<PathBuf>,
-    path: PathBuf,
+    path: &Path,
     mut files: Vec<PathBuf>,
-) -> anyhow::Result<()> {
+    merge: &mut Prompt,
+    delete: &mut Prompt,
+) -> anyhow::Result<bool> {
     files.sort_unstable();
 
     let filename = path.file_name().and_then(|v| v.to_str()).unwrap();
     let list_name = location.into().join(format!("{filename}.txt"));
-    let mut list = fs::File::create(&list_name)?;
+    let mut list = String::new();
 
     log::info!("Output: {:?}", list_name);
 
@@ -67,49 +92,49 @@ fn write_ffmpeg_merge(
         println!("{i}: {:?}: ", file);
     }
 
-    let mut stdin = io::stdin().lines();
-
-    print!("Merge? (Y/else): ");
-    io::stdout().flush()?;
+    let mut plan = Plan::new();
+    plan.write(&list_name, list);
+    plan.execute()?;
 
-    if let Some(Ok(line)) = stdin.next() {
-        if matches!(line.to_ascii_lowercase().trim(), "y" | "yes") {
-            if !exec_ffmpeg(list_name, path.join(format!("{}.mp4", filename))) {
-                return Ok(());
-            }
+    if !merge.ask(&format!("Merge {:?}?", filename))? {
+        return Ok(false);
+    }
 
-            print!("Delete splitted video files? (Y/else): ");
-            io::stdout().flush()?;
+    exec_ffmpeg(&list_name, &merged_path(path))?;
 
-            if let Some(Ok(line)) = stdin.next() {
-                if matches!(line.to_ascii_lowercase().trim(), "y" | "yes") {
-                    delete_videos(files);
-                }
-            }
-        }
+    if delete.ask("Delete splitted video files?")? {
+        delete_videos(files)?;
     }
 
-    print!("Delete splitted videos? (Y/else): ");
-    io::stdout().flush()?;
+    Ok(true)
+}
 
-    Ok(())
+/// The merged video goes into the folder of its parts, named after the folder
+fn merged_path(path: &Path) -> PathBuf {
+    let filename = path.file_name().unwrap_or_default().to_string_lossy();
+    path.join(format!("{filename}.mp4"))
 }
 
-fn exec_ffmpeg(list: PathBuf, output: PathBuf) -> bool {
-    Command::new("ffmpeg")
-        .args(["-safe", "0", "-f", "concat", "-i"])
-        .arg(list)
+fn exec_ffmpeg(list: &Path, output: &Path) -> ffmpeg_wrapper::Result<()> {
+    if macros::global().dry_run {
+        log::info!("ffmpeg {:?} => {:?}", list, output);
+        return Ok(());
+    }
+
+    Ffmpeg::new()
+        .args(["-safe", "0", "-f", "concat"])
+        .input(list)
         .args(["-c", "copy"])
-        .arg(output)
-        .spawn()
-        .unwrap()
-        .wait()
-        .unwrap()
-        .success()
+        .output(output)
+        .run()
 }
 
-fn delete_videos(files: Vec<PathBuf>) {
+fn delete_videos(files: Vec<PathBuf>) -> anyhow::Result<()> {
+    let mut plan = Plan::new();
+
     for file in files {
-        fs::remove_file(file).unwrap();
+        plan.delete(file);
     }
+
+    plan.execute()
 }
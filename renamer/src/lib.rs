@@ -1,9 +1,9 @@
 mod walkdir;
 
 use clap::*;
+use macros::plan::Plan;
+use macros::prompt::{self, Prompt};
 use std::cmp::Reverse;
-use std::fs;
-use std::io::{self, Write as _};
 use walkdir::WalkDir;
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
@@ -94,39 +94,31 @@ impl Args {
 
         todolist.sort_by_key(|(current, _new)| Reverse(current.components().count()));
 
-        for (i, (current, new)) in todolist.iter().enumerate() {
+        let mut plan = Plan::new();
+
+        for (current, new) in todolist {
             if self.delete {
-                log::info!("To Delete: #{i} {:#?}", &current);
+                plan.delete(current);
             } else {
-                log::info!("To Rename: #{i} {:#?}\n=> {:#?}\n", &current, &new);
+                plan.rename(current, new);
             }
         }
 
-        print!("Process? (Y/else): ");
-        io::stdout().flush()?;
-        let mut stdin = io::stdin().lines();
-        if let Some(Ok(line)) = stdin.next() {
-            if matches!(line.trim(), "y" | "yes") {
-                for (current, new) in todolist {
-                    if self.delete {
-                        if current.is_dir() {
-                            if let Err(why) = fs::remove_dir_all(current) {
-                                log::error!("Cannot delete directory\n{:#?}", why);
-                            }
-                        } else {
-                            if let Err(why) = fs::remove_file(current) {
-                                log::error!("Cannot delete file\n{:#?}", why);
-                            }
-                        }
-                    } else {
-                        if let Err(why) = fs::rename(current, new) {
-                            log::error!("Cannot renaming file\n{:#?}", why);
-                        }
-                    }
-                }
+        if macros::global().dry_run {
+            return plan.execute_reported();
+        }
+
+        prompt::check()?;
+
+        let mut prompt = Prompt::new();
+        let mut accepted = Plan::new();
+
+        for op in plan {
+            if prompt.ask(&format!("{op}?"))? {
+                accepted.push(op);
             }
         }
 
-        Ok(())
+        accepted.execute_reported()
     }
 }
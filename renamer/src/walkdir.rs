@@ -63,13 +63,13 @@ impl Iterator for WalkDir {
                         continue;
                     };
 
-                    if metadata.is_dir() {
-                        if !matches!(self.max_depth, Some(max) if max <= self.depth) {
-                            self.folders.push(NextDir {
-                                path: item.path(),
-                                depth: self.depth + 1,
-                            });
-                        }
+                    if metadata.is_dir()
+                        && !matches!(self.max_depth, Some(max) if max <= self.depth)
+                    {
+                        self.folders.push(NextDir {
+                            path: item.path(),
+                            depth: self.depth + 1,
+                        });
                     }
 
                     return Some(item);
@@ -1,5 +1,6 @@
 use anyhow::*;
 use clap::*;
+use macros::plan::Plan;
 use serde::Serialize;
 use std::fs;
 use std::io::{self, Read, Write};
@@ -38,11 +39,7 @@ impl Args {
             }
         }
 
-        let mut writer: Box<dyn Write> = match &self.output_file {
-            Some(name) => Box::new(fs::File::create(name)?) as Box<_>,
-            None => Box::new(io::stdout()) as Box<_>,
-        };
-
+        let mut writer = Vec::new();
         let data = parse(&s, self.format)?;
 
         macro_rules! typ {
@@ -58,6 +55,15 @@ impl Args {
 
         typ!(Json, Yaml, Toml);
 
+        match &self.output_file {
+            Some(name) => {
+                let mut plan = Plan::new();
+                plan.write(name, writer);
+                plan.execute()?;
+            }
+            None => io::stdout().write_all(&writer)?,
+        }
+
         Ok(())
     }
 }
@@ -0,0 +1,16 @@
+macros::lib_main!(
+    cbz2mihon,
+    downsize_image,
+    flatten_video_folder,
+    folder_intersect,
+    manga2pocket,
+    mergevideos,
+    renamer,
+    serde_convert,
+    to_720p,
+    to_folder,
+    to_mp3,
+    video_single_audio,
+    video_thumbnail,
+    webp2jpg,
+);
@@ -0,0 +1,198 @@
+//! The audio side of the encode: downmixing to stereo and loudness normalization.
+//!
+//! The loudness is measured over a whole track with `loudnorm` first, so the second run can
+//! correct it in one linear gain instead of adjusting it on the fly.
+
+use anyhow::{Context as _, Result};
+use ffmpeg_wrapper::probe::Stream;
+use ffmpeg_wrapper::{Ffmpeg, Probe};
+use macros::cancel;
+use serde::Deserialize;
+use std::path::Path;
+
+/// Integrated loudness of the output in LUFS, the one of EBU R128
+const TARGET_LOUDNESS: f64 = -23.0;
+
+/// Maximum true peak in dBTP
+const TRUE_PEAK: f64 = -1.0;
+
+/// Loudness range in LU, wide enough for most films to be corrected without compression
+const LOUDNESS_RANGE: f64 = 11.0;
+
+/// Used for the filtered streams when the audio would be copied otherwise
+const CODEC: &str = "aac";
+
+/// Of a filtered stream with up to two channels, more channels get twice as much
+const BITRATE: u64 = 192_000;
+const SURROUND_BITRATE: u64 = 384_000;
+
+/// The bitrate given to libopus
+const OPUS_BITRATE: u64 = 192_000;
+
+/// The mix used when a layout is downmixed, the center and surround channels are folded in
+const STEREO: &str = "aformat=channel_layouts=stereo";
+
+/// What `loudnorm` measured over a track, it prints the numbers as strings
+#[derive(Debug, Deserialize)]
+struct Measurement {
+    input_i: String,
+    input_tp: String,
+    input_lra: String,
+    input_thresh: String,
+    target_offset: String,
+}
+
+/// Output options for the audio streams of `input`, encoded with `codec` or copied
+pub fn args(
+    path: &Path,
+    input: &Probe,
+    codec: &str,
+    loudnorm: bool,
+    stereo: bool,
+) -> Result<Vec<String>> {
+    let mut args = vec!["-c:a".to_owned(), codec.to_owned()];
+
+    if codec == "libopus" {
+        args.extend(["-b:a".to_owned(), OPUS_BITRATE.to_string()]);
+    }
+
+    for (n, stream) in input.audios().enumerate() {
+        let channels = stream.channels.unwrap_or(2);
+        let mut chain = Vec::new();
+
+        if stereo && channels > 2 {
+            chain.push(STEREO.to_owned());
+        }
+
+        if loudnorm {
+            chain.extend(normalize(path, stream, &chain)?);
+        }
+
+        if chain.is_empty() {
+            continue;
+        }
+
+        args.extend([format!("-filter:a:{n}"), chain.join(",")]);
+
+        // A filtered stream cannot be copied
+        if codec == "copy" {
+            let bitrate = filtered_bitrate(channels, stereo);
+
+            args.extend([format!("-c:a:{n}"), CODEC.to_owned()]);
+            args.extend([format!("-b:a:{n}"), bitrate.to_string()]);
+        }
+    }
+
+    Ok(args)
+}
+
+/// The bitrate `stream` is going to have in the output, `None` when it is up to the encoder or
+/// unknown
+pub fn bitrate(stream: &Stream, codec: &str, loudnorm: bool, stereo: bool) -> Option<u64> {
+    let channels = stream.channels.unwrap_or(2);
+
+    match codec {
+        "copy" if loudnorm || (stereo && channels > 2) => Some(filtered_bitrate(channels, stereo)),
+        "copy" => stream.bit_rate,
+        "libopus" => Some(OPUS_BITRATE),
+        _ => None,
+    }
+}
+
+/// Of a stream encoded with aac because it is filtered
+fn filtered_bitrate(channels: u32, stereo: bool) -> u64 {
+    match stereo || channels <= 2 {
+        true => BITRATE,
+        false => SURROUND_BITRATE,
+    }
+}
+
+/// The filters correcting the loudness of `stream` once it went through `chain`, `None` for a
+/// silent track as there is nothing to bring up
+fn normalize(path: &Path, stream: &Stream, chain: &[String]) -> Result<Option<String>> {
+    let target = format!("I={TARGET_LOUDNESS}:TP={TRUE_PEAK}:LRA={LOUDNESS_RANGE}");
+
+    let measure = chain
+        .iter()
+        .cloned()
+        .chain([format!("loudnorm={target}:print_format=json")])
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let log = Ffmpeg::new()
+        .echo(false)
+        .args(["-loglevel", "info", "-nostats"])
+        .input(path)
+        .arg("-map")
+        .arg(format!("0:{}", stream.index))
+        .args(["-af", &measure, "-f", "null"])
+        .output("-")
+        .cancel_when(cancel::is_cancelled)
+        .run_for_log()
+        .with_context(|| format!("Cannot measure the loudness of stream {}", stream.index))?;
+
+    let measured = parse(&log)
+        .with_context(|| format!("Cannot measure the loudness of stream {}", stream.index))?;
+
+    // -inf when silent
+    if measured
+        .input_i
+        .parse::<f64>()
+        .map_or(true, |v| !v.is_finite())
+    {
+        return Ok(None);
+    }
+
+    // loudnorm works at 192kHz, back to the rate of the source
+    let sample_rate = stream.sample_rate.unwrap_or(48_000);
+
+    Ok(Some(format!(
+        "loudnorm={target}:measured_I={}:measured_TP={}:measured_LRA={}:measured_thresh={}:\
+         offset={}:linear=true,aresample={sample_rate}",
+        measured.input_i,
+        measured.input_tp,
+        measured.input_lra,
+        measured.input_thresh,
+        measured.target_offset
+    )))
+}
+
+/// The JSON block `loudnorm` prints at the end of the log
+fn parse(log: &str) -> Result<Measurement> {
+    let start = log.rfind('{').context("No measurement in the log")?;
+    let end = log[start..]
+        .find('}')
+        .context("No measurement in the log")?
+        + start;
+    Ok(serde_json::from_str(&log[start..=end])?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitrates() {
+        let stream = |channels, bit_rate| Stream {
+            channels: Some(channels),
+            bit_rate,
+            ..Default::default()
+        };
+
+        let (stereo, surround) = (stream(2, Some(128_000)), stream(6, Some(640_000)));
+
+        assert_eq!(bitrate(&stereo, "copy", false, false), Some(128_000));
+        assert_eq!(bitrate(&surround, "copy", false, true), Some(BITRATE));
+        assert_eq!(
+            bitrate(&surround, "copy", true, false),
+            Some(SURROUND_BITRATE)
+        );
+        assert_eq!(bitrate(&stereo, "copy", true, true), Some(BITRATE));
+        assert_eq!(bitrate(&stream(2, None), "copy", false, false), None);
+        assert_eq!(
+            bitrate(&surround, "libopus", true, true),
+            Some(OPUS_BITRATE)
+        );
+        assert_eq!(bitrate(&surround, "ac3", false, false), None);
+    }
+}
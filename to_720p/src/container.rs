@@ -0,0 +1,148 @@
+use crate::verify::Expected;
+use ffmpeg_wrapper::probe::{CodecType, Probe, Stream};
+
+/// Subtitle codecs that can be turned into `mov_text`, the others are images
+const TEXT_SUBTITLES: &[&str] = &["subrip", "srt", "ass", "ssa", "webvtt", "mov_text", "text"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    Mkv,
+    Mp4,
+}
+
+impl Container {
+    /// MKV sources stay MKV so nothing of them is lost, everything else becomes MP4
+    pub fn for_input(ext: &str, force_mp4: bool) -> Self {
+        match ext {
+            "mkv" if !force_mp4 => Self::Mkv,
+            _ => Self::Mp4,
+        }
+    }
+
+    pub fn ext(&self) -> &'static str {
+        match self {
+            Self::Mkv => "mkv",
+            Self::Mp4 => "mp4",
+        }
+    }
+
+    /// The `-map` and stream codec options that carry the input into this container, along with
+    /// the streams the output should end up with.
+    ///
+    /// The cover pictures come after every other video stream and are copied as they are, they
+    /// are not counted as videos.
+    pub fn mapping(&self, input: &Probe) -> (Vec<String>, Expected) {
+        let videos = input.videos().collect::<Vec<_>>();
+        let audios = input.audios().collect::<Vec<_>>();
+        let subtitles = input.subtitles().collect::<Vec<_>>();
+
+        let mut args = match self {
+            // Everything, including the fonts of the ASS subtitles. 0:V leaves out the covers
+            Self::Mkv => [
+                "-map", "0:V?", "-map", "0:a?", "-map", "0:s?", "-map", "0:t?",
+            ]
+            .into_iter()
+            .chain(["-c:s", "copy", "-c:t", "copy"])
+            .map(String::from)
+            .collect(),
+            Self::Mp4 => Vec::new(),
+        };
+
+        let mut expected = Expected {
+            videos: videos.len(),
+            audios: audios.len(),
+            subtitles: subtitles.len(),
+        };
+
+        // MP4 takes neither attachments nor image subtitles
+        if *self == Self::Mp4 {
+            let (text, image): (Vec<&Stream>, Vec<&Stream>) =
+                subtitles.into_iter().partition(|v| {
+                    let codec = v.codec_name.as_deref().unwrap_or_default();
+                    TEXT_SUBTITLES.contains(&codec)
+                });
+
+            for stream in image {
+                log::warn!(
+                    "Dropping the {} subtitle #{}, MP4 cannot hold it",
+                    stream.codec_name.as_deref().unwrap_or("unknown"),
+                    stream.index
+                );
+            }
+
+            for stream in videos.iter().chain(&audios).chain(&text) {
+                args.extend(["-map".to_owned(), format!("0:{}", stream.index)]);
+            }
+
+            if !text.is_empty() {
+                args.extend(["-c:s", "mov_text"].map(String::from));
+            }
+
+            expected.subtitles = text.len();
+        }
+
+        let covers = input
+            .of_type(CodecType::Video)
+            .filter(|v| v.disposition.attached_pic());
+
+        for (n, cover) in (videos.len()..).zip(covers) {
+            args.extend(["-map".to_owned(), format!("0:{}", cover.index)]);
+            args.extend([format!("-c:v:{n}"), "copy".to_owned()]);
+            args.extend([format!("-disposition:v:{n}"), "attached_pic".to_owned()]);
+        }
+
+        args.extend(["-map_chapters", "0", "-map_metadata", "0"].map(String::from));
+
+        (args, expected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A video, an audio track, a cover picture and image and text subtitles
+    fn probe() -> Probe {
+        serde_json::from_str(
+            r#"{ "streams": [
+                { "index": 0, "codec_type": "video", "codec_name": "h264" },
+                { "index": 1, "codec_type": "audio", "codec_name": "aac" },
+                { "index": 2, "codec_type": "video", "codec_name": "mjpeg",
+                  "disposition": { "attached_pic": 1 } },
+                { "index": 3, "codec_type": "subtitle", "codec_name": "hdmv_pgs_subtitle" },
+                { "index": 4, "codec_type": "subtitle", "codec_name": "ass" }
+            ] }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn mkv() {
+        let (args, expected) = Container::Mkv.mapping(&probe());
+
+        assert_eq!(
+            args.join(" "),
+            "-map 0:V? -map 0:a? -map 0:s? -map 0:t? -c:s copy -c:t copy \
+             -map 0:2 -c:v:1 copy -disposition:v:1 attached_pic -map_chapters 0 -map_metadata 0"
+        );
+        assert_eq!(
+            (expected.videos, expected.audios, expected.subtitles),
+            (1, 1, 2)
+        );
+    }
+
+    #[test]
+    fn mp4() {
+        let (args, expected) = Container::Mp4.mapping(&probe());
+
+        assert_eq!(
+            args.join(" "),
+            "-map 0:0 -map 0:1 -map 0:4 -c:s mov_text \
+             -map 0:2 -c:v:1 copy -disposition:v:1 attached_pic -map_chapters 0 -map_metadata 0"
+        );
+        assert_eq!(
+            (expected.videos, expected.audios, expected.subtitles),
+            (1, 1, 1)
+        );
+    }
+}
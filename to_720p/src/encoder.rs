@@ -0,0 +1,448 @@
+use anyhow::{bail, Result};
+use ffmpeg_wrapper::Ffmpeg;
+use std::fmt;
+use std::path::Path;
+
+/// Render node used for the vaapi encoders
+const VAAPI_DEVICE: &str = "/dev/dri/renderD128";
+
+/// Source of the test encode, a fraction of a second of black frames
+const TEST_INPUT: &str = "color=c=black:s=640x360:r=30:d=0.2";
+
+/// How much the quality level is lowered when an output looks too degraded
+const QUALITY_STEP: u8 = 3;
+
+/// How much the bitrate is raised when an output looks too degraded
+const BITRATE_STEP: f64 = 1.4;
+
+/// The binary unit of `--target-size`, only used with an explicit `KiB`, `MiB` or `GiB`
+const KIB: f64 = 1024.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Nvenc,
+    Vaapi,
+    Software,
+    /// Given by name on the command line, nothing is known about it
+    Other,
+}
+
+impl Kind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Nvenc => "nvenc",
+            Self::Vaapi => "vaapi",
+            Self::Software => "software",
+            Self::Other => "other",
+        }
+    }
+
+    /// How many encodes the hardware takes at once, consumer NVIDIA cards refuse more than 3
+    /// sessions with older drivers
+    fn max_sessions(&self) -> Option<usize> {
+        match self {
+            Self::Nvenc => Some(3),
+            Self::Vaapi | Self::Software | Self::Other => None,
+        }
+    }
+}
+
+/// How the encoder decides how many bits to spend
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rate {
+    /// Constant quality level in the scale of the encoder, lower is better
+    Quality(u8),
+    /// Average bitrate in bits per second
+    Bitrate(u64),
+}
+
+impl fmt::Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Quality(v) => write!(f, "quality {v}"),
+            Self::Bitrate(v) => write!(f, "{}kbit/s", v / 1000),
+        }
+    }
+}
+
+/// One run of ffmpeg over the input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pass {
+    Only,
+    /// Analysis only, the output is thrown away
+    First,
+    Second,
+}
+
+/// A video encoder together with the options that give a decent quality out of it
+#[derive(Debug, Clone)]
+pub struct Encoder {
+    pub name: String,
+    pub kind: Kind,
+    pub preset: Option<String>,
+    pub rate: Option<Rate>,
+    /// Only used together with a bitrate
+    pub two_pass: bool,
+}
+
+/// The encoders tried by `auto`, best first
+const PREFERENCE: &[(&str, Kind, Option<&str>, Option<u8>)] = &[
+    ("h264_nvenc", Kind::Nvenc, Some("p6"), Some(26)),
+    ("hevc_nvenc", Kind::Nvenc, Some("p6"), Some(28)),
+    ("h264_vaapi", Kind::Vaapi, None, Some(24)),
+    ("hevc_vaapi", Kind::Vaapi, None, Some(26)),
+    ("libx264", Kind::Software, Some("medium"), Some(23)),
+    ("libx265", Kind::Software, Some("medium"), Some(26)),
+    ("libsvtav1", Kind::Software, Some("8"), Some(32)),
+];
+
+impl Encoder {
+    /// The encoder with its presets, an unknown name is used as it is
+    pub fn from_name(name: &str) -> Self {
+        let known = PREFERENCE.iter().find(|(v, ..)| *v == name);
+
+        match known {
+            Some(&(name, kind, preset, quality)) => Self {
+                name: name.to_owned(),
+                kind,
+                preset: preset.map(String::from),
+                rate: quality.map(Rate::Quality),
+                two_pass: false,
+            },
+            None => Self {
+                name: name.to_owned(),
+                kind: Kind::Other,
+                preset: None,
+                rate: None,
+                two_pass: false,
+            },
+        }
+    }
+
+    /// Pick the best encoder that ffmpeg has and that actually works on this machine
+    pub fn detect() -> Result<Self> {
+        let available = ffmpeg_wrapper::encoders()?;
+        let mut rejected = Vec::new();
+
+        for &(name, ..) in PREFERENCE {
+            if !available.iter().any(|v| v == name) {
+                rejected.push(format!("{name} is not built into ffmpeg"));
+                continue;
+            }
+
+            let encoder = Self::from_name(name);
+
+            match encoder.test() {
+                Ok(()) => {
+                    for why in &rejected {
+                        log::info!("Skipped {why}");
+                    }
+
+                    log::info!("Using {name}, the best encoder that passed a test encode");
+                    return Ok(encoder);
+                }
+                Err(why) => {
+                    let reason = why.to_string();
+                    let reason = reason.lines().last().unwrap_or_default();
+                    rejected.push(format!("{name}, its test encode failed: {reason}"));
+                }
+            }
+        }
+
+        for why in &rejected {
+            log::warn!("Skipped {why}");
+        }
+
+        bail!("None of the known video encoders works")
+    }
+
+    /// How many encodes may run at once with this encoder, from the `--encoder-jobs` values
+    /// given by encoder name or kind, then from the defaults of its kind
+    pub fn max_sessions(&self, limits: &[(String, usize)]) -> Option<usize> {
+        limits
+            .iter()
+            .rev()
+            .find(|(key, _)| *key == self.name || key == self.kind.name())
+            .map(|&(_, limit)| limit)
+            .or_else(|| self.kind.max_sessions())
+    }
+
+    /// Encode a few frames to see whether the encoder works, e.g. whether there is a GPU for it
+    pub fn test(&self) -> Result<()> {
+        let ffmpeg = self
+            .input_args(Ffmpeg::new().echo(false).args(["-loglevel", "error"]))
+            .args(["-f", "lavfi"])
+            .input(TEST_INPUT);
+
+        let ffmpeg = ffmpeg
+            .args(self.filter_args(None, 1))
+            .args(self.args(Pass::Only, Path::new("")))
+            .args(["-f", "null"])
+            .output("-");
+        ffmpeg.run()?;
+
+        Ok(())
+    }
+
+    /// The same encoder a step up in quality, `None` when there is no rate to raise
+    pub fn higher_quality(&self) -> Option<Self> {
+        let rate = match self.rate? {
+            Rate::Quality(v) => Rate::Quality(v.checked_sub(QUALITY_STEP).filter(|&v| v > 0)?),
+            Rate::Bitrate(v) => Rate::Bitrate((v as f64 * BITRATE_STEP) as u64),
+        };
+
+        Some(Self {
+            rate: Some(rate),
+            ..self.clone()
+        })
+    }
+
+    /// Global options that have to come before the input
+    pub fn input_args(&self, ffmpeg: Ffmpeg) -> Ffmpeg {
+        match self.kind {
+            Kind::Vaapi => ffmpeg.args(["-vaapi_device", VAAPI_DEVICE]),
+            _ => ffmpeg,
+        }
+    }
+
+    /// The filters of the first `videos` video streams of the output, made of the given filter
+    /// chain and whatever the encoder needs. The cover pictures mapped after them are copied, and
+    /// a copied stream cannot be filtered
+    pub fn filter_args(&self, filter: Option<&str>, videos: usize) -> Vec<String> {
+        let upload = match self.kind {
+            Kind::Vaapi => Some("format=nv12,hwupload"),
+            _ => None,
+        };
+
+        let chain = [filter, upload].into_iter().flatten().collect::<Vec<_>>();
+
+        if chain.is_empty() {
+            return Vec::new();
+        }
+
+        let chain = chain.join(",");
+
+        (0..videos)
+            .flat_map(|n| [format!("-filter:v:{n}"), chain.clone()])
+            .collect()
+    }
+
+    /// The runs of ffmpeg needed for one video.
+    ///
+    /// nvenc does its two passes within a single run, the vaapi encoders and SVT-AV1 are left
+    /// with a single pass at the average bitrate.
+    pub fn passes(&self) -> &'static [Pass] {
+        let bitrate = matches!(self.rate, Some(Rate::Bitrate(_)));
+
+        match self.two_pass && bitrate && self.separate_passes() {
+            true => &[Pass::First, Pass::Second],
+            false => &[Pass::Only],
+        }
+    }
+
+    /// Whether two passes are done as two runs of ffmpeg
+    pub fn separate_passes(&self) -> bool {
+        match self.kind {
+            Kind::Software => self.name != "libsvtav1",
+            Kind::Other => true,
+            Kind::Nvenc | Kind::Vaapi => false,
+        }
+    }
+
+    /// `-c:v` with the rate control options of the encoder.
+    ///
+    /// `passlog` is the prefix of the statistics files shared by the two passes.
+    pub fn args(&self, pass: Pass, passlog: &Path) -> Vec<String> {
+        let mut args = vec!["-c:v".to_owned(), self.name.clone()];
+
+        if let Some(ref preset) = self.preset {
+            args.extend(["-preset".to_owned(), preset.clone()]);
+        }
+
+        match (self.rate, self.kind) {
+            (None, _) => {}
+            (Some(Rate::Quality(q)), Kind::Nvenc) => {
+                args.extend(["-rc", "vbr", "-cq", &q.to_string(), "-b:v", "0"].map(String::from));
+            }
+            (Some(Rate::Quality(q)), Kind::Vaapi) => {
+                args.extend(["-rc_mode", "CQP", "-qp", &q.to_string()].map(String::from));
+            }
+            (Some(Rate::Quality(q)), _) => args.extend(["-crf".to_owned(), q.to_string()]),
+            (Some(Rate::Bitrate(bitrate)), kind) => {
+                let rate = |v: u64| format!("{}k", v / 1000);
+
+                if kind == Kind::Vaapi {
+                    args.extend(["-rc_mode".to_owned(), "VBR".to_owned()]);
+                }
+
+                if kind == Kind::Nvenc {
+                    args.extend(["-rc".to_owned(), "vbr".to_owned()]);
+
+                    if self.two_pass {
+                        args.extend(["-multipass".to_owned(), "fullres".to_owned()]);
+                    }
+                }
+
+                args.extend([
+                    "-b:v".to_owned(),
+                    rate(bitrate),
+                    "-maxrate".to_owned(),
+                    rate(bitrate * 3 / 2),
+                    "-bufsize".to_owned(),
+                    rate(bitrate * 2),
+                ]);
+            }
+        }
+
+        let number = match pass {
+            Pass::Only => return args,
+            Pass::First => 1,
+            Pass::Second => 2,
+        };
+
+        match self.name.as_str() {
+            // libx265 ignores `-pass`, it has to be told through its own parameters
+            "libx265" => {
+                args.extend([
+                    "-x265-params".to_owned(),
+                    format!("pass={number}:stats={}-x265.log", passlog.display()),
+                ]);
+            }
+            _ => {
+                args.extend(["-pass".to_owned(), number.to_string()]);
+                args.extend(["-passlogfile".to_owned(), passlog.display().to_string()]);
+            }
+        }
+
+        args
+    }
+}
+
+/// An `--encoder-jobs` value like `nvenc=2` or `libx265=1`
+pub fn parse_sessions(s: &str) -> Result<(String, usize), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Expected ENCODER=N, got {s:?}"))?;
+
+    match value.trim().parse::<usize>() {
+        Ok(v) if v > 0 => Ok((key.trim().to_owned(), v)),
+        _ => Err(format!("{value:?} is not a positive number")),
+    }
+}
+
+/// A bitrate like `2500k`, `2.5M` or `800000`, in bits per second. The units are decimal like
+/// in ffmpeg
+pub fn parse_bitrate(s: &str) -> Result<u64, String> {
+    let (number, unit) = split_unit(s);
+
+    let Some(multiplier) = prefix(&unit.to_ascii_lowercase()) else {
+        return Err(format!("Unknown unit {unit:?}, expected k, M or G"));
+    };
+
+    parse_number(number).map(|v| (v * multiplier) as u64)
+}
+
+/// A file size like `700M`, `700MB` or `1.5GiB`, in bytes. `k`, `M` and `G` are decimal like
+/// for bitrates, with or without a `B`. Only `KiB`, `MiB` and `GiB` are binary
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let (number, unit) = split_unit(s);
+
+    let multiplier = match unit.to_ascii_lowercase().as_str() {
+        "kib" => Some(KIB),
+        "mib" => Some(KIB * KIB),
+        "gib" => Some(KIB * KIB * KIB),
+        "b" => Some(1.0),
+        unit => prefix(unit.strip_suffix('b').unwrap_or(unit)),
+    };
+
+    let Some(multiplier) = multiplier else {
+        return Err(format!(
+            "Unknown unit {unit:?}, expected k, M or G, kB, MB or GB, or KiB, MiB or GiB"
+        ));
+    };
+
+    parse_number(number).map(|v| (v * multiplier) as u64)
+}
+
+/// The decimal prefixes shared by bitrates and sizes, lowercased
+fn prefix(unit: &str) -> Option<f64> {
+    match unit {
+        "" => Some(1.0),
+        "k" => Some(1e3),
+        "m" => Some(1e6),
+        "g" => Some(1e9),
+        _ => None,
+    }
+}
+
+fn split_unit(s: &str) -> (&str, &str) {
+    let s = s.trim();
+    let at = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+
+    (s[..at].trim(), s[at..].trim())
+}
+
+fn parse_number(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(v) if v > 0.0 => Ok(v),
+        _ => Err(format!("{s:?} is not a positive number")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitrate() {
+        assert_eq!(parse_bitrate("800000"), Ok(800_000));
+        assert_eq!(parse_bitrate("2500k"), Ok(2_500_000));
+        assert_eq!(parse_bitrate("2500K"), Ok(2_500_000));
+        assert_eq!(parse_bitrate("2.5M"), Ok(2_500_000));
+        assert_eq!(parse_bitrate(" 2.5 m "), Ok(2_500_000));
+
+        assert_eq!(parse_bitrate("1G"), Ok(1_000_000_000));
+
+        assert!(parse_bitrate("2.5T").is_err());
+        assert!(parse_bitrate("2500kB").is_err());
+        assert!(parse_bitrate("0k").is_err());
+        assert!(parse_bitrate("k").is_err());
+        assert!(parse_bitrate("-1M").is_err());
+    }
+
+    #[test]
+    fn size() {
+        assert_eq!(parse_size("1024"), Ok(1024));
+        assert_eq!(parse_size("1024B"), Ok(1024));
+        assert_eq!(parse_size("700M"), Ok(700_000_000));
+        assert_eq!(parse_size("700MB"), Ok(700_000_000));
+        assert_eq!(parse_size("4.7GB"), Ok(4_700_000_000));
+        assert_eq!(parse_size("1.5G"), Ok(1_500_000_000));
+        assert_eq!(parse_size("64kB"), Ok(64_000));
+        assert_eq!(parse_size("512k"), Ok(512_000));
+
+        assert_eq!(parse_size("700MiB"), Ok(700 * 1024 * 1024));
+        assert_eq!(parse_size("1.5GiB"), Ok(1536 * 1024 * 1024));
+        assert_eq!(parse_size("64 kib"), Ok(64 * 1024));
+
+        assert!(parse_size("1T").is_err());
+        assert!(parse_size("1Mi").is_err());
+        assert!(parse_size("0M").is_err());
+        assert!(parse_size("MB").is_err());
+        assert!(parse_size("1.2.3M").is_err());
+    }
+
+    #[test]
+    fn sessions() {
+        assert_eq!(parse_sessions("nvenc=2"), Ok(("nvenc".to_owned(), 2)));
+        assert_eq!(
+            parse_sessions(" libx265 = 1 "),
+            Ok(("libx265".to_owned(), 1))
+        );
+
+        assert!(parse_sessions("nvenc").is_err());
+        assert!(parse_sessions("nvenc=0").is_err());
+    }
+}
@@ -0,0 +1,434 @@
+//! The video filter chain: deinterlacing, cropping of the black bars and scaling down to the
+//! target height.
+//!
+//! Whether a video is interlaced and where its bars are is found by running `cropdetect` and
+//! `idet` on a few segments spread over it.
+
+use anyhow::{Context as _, Result};
+use clap::ValueEnum;
+use ffmpeg_wrapper::probe::{Rational, Stream};
+use ffmpeg_wrapper::Ffmpeg;
+use macros::cancel::{self, Cancelled};
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
+/// Where the analyzed segments start, as a share of the duration
+const SAMPLE_POSITIONS: &[f64] = &[0.1, 0.3, 0.5, 0.7, 0.9];
+
+/// Length of each analyzed segment
+const SAMPLE_LENGTH: Duration = Duration::from_secs(4);
+
+/// Bars thinner than this share of the frame are kept, they cost next to nothing and are often
+/// only the noisy edge of a capture
+const MIN_CROP: f64 = 0.02;
+
+/// Share of the classified frames that has to be interlaced for the video to be deinterlaced
+const INTERLACED_RATIO: f64 = 0.5;
+
+/// Below this the picture is not worth watching
+const MIN_HEIGHT: u32 = 144;
+
+/// Named heights for `--height`
+const HEIGHT_PRESETS: &[(&str, u32)] = &[("sd", 480), ("hd", 720), ("fhd", 1080)];
+
+/// The filter used on interlaced videos
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Deinterlacer {
+    Bwdif,
+    Yadif,
+    /// Never deinterlace, and skip the detection
+    Off,
+}
+
+impl Deinterlacer {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Bwdif => "bwdif",
+            Self::Yadif => "yadif",
+            Self::Off => "off",
+        }
+    }
+}
+
+/// The decoded frames of a video
+#[derive(Debug, Clone, Copy)]
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    /// Width over height of a pixel, anamorphic videos are stored squeezed and stretched back
+    /// when shown
+    pub sample_aspect: f64,
+}
+
+impl Frame {
+    pub fn from_stream(stream: &Stream) -> Option<Self> {
+        // ffprobe says 0:1 or N/A when it does not know
+        let sample_aspect = stream
+            .sample_aspect_ratio
+            .as_deref()
+            .and_then(|v| v.parse::<Rational>().ok())
+            .filter(|v| v.num > 0 && v.den > 0)
+            .map_or(1.0, f64::from);
+
+        Some(Self {
+            width: stream.width.filter(|&v| v > 0)?,
+            height: stream.height.filter(|&v| v > 0)?,
+            sample_aspect,
+        })
+    }
+
+    /// Width of the frames as they are shown
+    pub fn display_width(&self) -> u32 {
+        (self.width as f64 * self.sample_aspect).round() as u32
+    }
+}
+
+/// The field an interlaced frame starts with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Top,
+    Bottom,
+}
+
+/// The picture inside the black bars
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crop {
+    pub width: u32,
+    pub height: u32,
+    pub x: u32,
+    pub y: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Analysis {
+    /// `None` when there are no bars worth cropping
+    pub crop: Option<Crop>,
+    /// The first field when the video is interlaced
+    pub interlaced: Option<Field>,
+}
+
+impl fmt::Display for Analysis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.crop {
+            Some(v) => write!(f, "crop to {}x{} at {},{}", v.width, v.height, v.x, v.y)?,
+            None => write!(f, "no black bars")?,
+        }
+
+        match self.interlaced {
+            Some(Field::Top) => write!(f, ", interlaced top field first"),
+            Some(Field::Bottom) => write!(f, ", interlaced bottom field first"),
+            None => write!(f, ", progressive"),
+        }
+    }
+}
+
+/// Look for black bars when `crop` is set, and for interlacing when `interlace` is set
+pub fn analyze(
+    path: &Path,
+    (width, height): (u32, u32),
+    duration: Duration,
+    crop: bool,
+    interlace: bool,
+) -> Result<Analysis> {
+    let filters = [
+        crop.then_some("cropdetect=limit=24:round=2:reset=0"),
+        interlace.then_some("idet"),
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>();
+
+    if filters.is_empty() {
+        return Ok(Analysis::default());
+    }
+
+    let filters = filters.join(",");
+
+    // Union of the picture of every segment, as x1, x2, y1, y2
+    let mut picture: Option<(u32, u32, u32, u32)> = None;
+    let (mut tff, mut bff, mut progressive) = (0, 0, 0);
+
+    for &position in SAMPLE_POSITIONS {
+        if cancel::is_cancelled() {
+            return Err(Cancelled.into());
+        }
+
+        let start = duration.mul_f64(position);
+        let log = sample(path, start, &filters).with_context(|| {
+            format!("Cannot analyze the segment at {:.1}s", start.as_secs_f64())
+        })?;
+
+        for line in log.lines() {
+            if line.contains("Parsed_cropdetect") {
+                let bounds = (
+                    value(line, "x1"),
+                    value(line, "x2"),
+                    value(line, "y1"),
+                    value(line, "y2"),
+                );
+
+                // reset=0 makes every line hold the bounds of the whole segment so far, a black
+                // segment has them inverted
+                if let (Some(x1), Some(x2), Some(y1), Some(y2)) = bounds {
+                    if x1 <= x2 && y1 <= y2 {
+                        let (x1, x2, y1, y2) = (x1 as u32, x2 as u32, y1 as u32, y2 as u32);
+                        picture = Some(match picture {
+                            Some(v) => (v.0.min(x1), v.1.max(x2), v.2.min(y1), v.3.max(y2)),
+                            None => (x1, x2, y1, y2),
+                        });
+                    }
+                }
+            }
+
+            if line.contains("Multi frame detection:") {
+                tff += value(line, "TFF").unwrap_or(0);
+                bff += value(line, "BFF").unwrap_or(0);
+                progressive += value(line, "Progressive").unwrap_or(0);
+            }
+        }
+    }
+
+    let interlaced = tff + bff;
+    let classified = interlaced + progressive;
+
+    Ok(Analysis {
+        crop: picture.and_then(|v| crop_of(width, height, v)),
+        interlaced: match classified > 0
+            && interlaced as f64 / classified as f64 >= INTERLACED_RATIO
+        {
+            true if tff >= bff => Some(Field::Top),
+            true => Some(Field::Bottom),
+            false => None,
+        },
+    })
+}
+
+/// The `-vf` chain for a video, `None` when it needs no filter. The output has `height` lines on
+/// its shorter side, or keeps its size when it is smaller already
+pub fn chain(
+    frame: Frame,
+    analysis: &Analysis,
+    deinterlacer: Deinterlacer,
+    height: u32,
+) -> Option<String> {
+    let mut chain = Vec::new();
+
+    // Before the crop, which could otherwise swap the fields
+    if let (Some(field), Deinterlacer::Bwdif | Deinterlacer::Yadif) =
+        (analysis.interlaced, deinterlacer)
+    {
+        let parity = match field {
+            Field::Top => "tff",
+            Field::Bottom => "bff",
+        };
+
+        chain.push(format!(
+            "{}=mode=send_frame:parity={parity}",
+            deinterlacer.name()
+        ));
+    }
+
+    let (mut out_width, mut out_height) = (frame.width, frame.height);
+
+    if let Some(crop) = analysis.crop {
+        chain.push(format!(
+            "crop={}:{}:{}:{}",
+            crop.width, crop.height, crop.x, crop.y
+        ));
+        (out_width, out_height) = (crop.width, crop.height);
+    }
+
+    // Scaled by how much the whole frame would be, a cropped 1920x800 film ends up 1280x534 at
+    // 720 rather than 1728x720. The shorter side is taken as shown, not as stored
+    let shorter = frame.display_width().min(frame.height);
+
+    if shorter > height {
+        let factor = height as f64 / shorter as f64;
+
+        // Square pixels out, the stretch of anamorphic videos goes into the width
+        chain.push(format!(
+            "scale={}:{},setsar=1",
+            even(out_width as f64 * frame.sample_aspect * factor),
+            even(out_height as f64 * factor)
+        ));
+    }
+
+    match chain.is_empty() {
+        true => None,
+        false => Some(chain.join(",")),
+    }
+}
+
+/// `--height` from a number of lines like 720 or 720p, or a preset name like hd
+pub fn parse_height(s: &str) -> Result<u32, String> {
+    let s = s.trim().to_ascii_lowercase();
+
+    let height = match HEIGHT_PRESETS.iter().find(|(name, _)| *name == s) {
+        Some(&(_, height)) => height,
+        None => s.strip_suffix('p').unwrap_or(&s).parse().map_err(|_| {
+            let presets = HEIGHT_PRESETS
+                .iter()
+                .map(|(name, height)| format!("{name} ({height})"))
+                .collect::<Vec<_>>();
+
+            format!(
+                "Invalid height {s:?}, expected a number like 720 or one of {}",
+                presets.join(", ")
+            )
+        })?,
+    };
+
+    if height < MIN_HEIGHT || height % 2 == 1 {
+        return Err(format!(
+            "The height has to be even and at least {MIN_HEIGHT}"
+        ));
+    }
+
+    Ok(height)
+}
+
+/// Run the analysis filters over one segment, giving back what they logged
+fn sample(path: &Path, start: Duration, filters: &str) -> Result<String> {
+    let log = Ffmpeg::new()
+        .echo(false)
+        .args(["-loglevel", "info", "-nostats"])
+        .arg("-ss")
+        .arg(format!("{:.3}", start.as_secs_f64()))
+        .arg("-t")
+        .arg(format!("{:.3}", SAMPLE_LENGTH.as_secs_f64()))
+        .input(path)
+        .args(["-map", "0:v:0", "-vf", filters, "-f", "null"])
+        .output("-")
+        .cancel_when(cancel::is_cancelled)
+        .run_for_log()?;
+
+    Ok(log)
+}
+
+/// The number after `key:` in a line logged by a filter, with or without spaces in between
+fn value(line: &str, key: &str) -> Option<i64> {
+    let (_, rest) = line.split_once(&format!(" {key}:"))?;
+    rest.split_whitespace().next()?.parse().ok()
+}
+
+/// The crop of the picture in `x1..=x2`, `y1..=y2`, on even lines and columns for the chroma.
+/// A side is only cropped when its bars are thick enough.
+fn crop_of(width: u32, height: u32, (x1, x2, y1, y2): (u32, u32, u32, u32)) -> Option<Crop> {
+    let side = |size: u32, start: u32, end: u32| {
+        let start = start.min(size - 1) & !1;
+        let len = (end.min(size - 1) + 1 - start)
+            .next_multiple_of(2)
+            .min(size - start);
+
+        match (size - len) as f64 >= size as f64 * MIN_CROP {
+            true => (len, start),
+            false => (size, 0),
+        }
+    };
+
+    let (crop_width, x) = side(width, x1, x2);
+    let (crop_height, y) = side(height, y1, y2);
+
+    match (crop_width, crop_height) == (width, height) {
+        true => None,
+        false => Some(Crop {
+            width: crop_width,
+            height: crop_height,
+            x,
+            y,
+        }),
+    }
+}
+
+/// The nearest even number, most encoders cannot take odd dimensions
+fn even(value: f64) -> u32 {
+    ((value / 2.0).round() as u32 * 2).max(2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn height() {
+        assert_eq!(parse_height("720"), Ok(720));
+        assert_eq!(parse_height("1080p"), Ok(1080));
+        assert_eq!(parse_height(" 480P "), Ok(480));
+        assert_eq!(parse_height("sd"), Ok(480));
+        assert_eq!(parse_height("HD"), Ok(720));
+        assert_eq!(parse_height("fhd"), Ok(1080));
+        assert_eq!(parse_height("144"), Ok(144));
+
+        assert!(parse_height("142").is_err());
+        assert!(parse_height("721").is_err());
+        assert!(parse_height("4k").is_err());
+        assert!(parse_height("").is_err());
+    }
+
+    #[test]
+    fn chains() {
+        let frame = |width, height, sample_aspect| Frame {
+            width,
+            height,
+            sample_aspect,
+        };
+
+        let cropped = |width, height, x, y| Analysis {
+            crop: Some(Crop {
+                width,
+                height,
+                x,
+                y,
+            }),
+            interlaced: None,
+        };
+
+        let none = Analysis::default();
+        let interlaced = Analysis {
+            crop: None,
+            interlaced: Some(Field::Bottom),
+        };
+
+        let anamorphic = 32.0 / 27.0;
+
+        #[rustfmt::skip]
+        let cases = [
+            // Square pixels
+            (frame(1920, 1080, 1.0), none, 720, Some("scale=1280:720,setsar=1")),
+            (frame(1280, 720, 1.0), none, 720, None),
+            (frame(1080, 1920, 1.0), none, 720, Some("scale=720:1280,setsar=1")),
+            // An NTSC DVD shown at 853x480 keeps its size, and is stretched when scaled
+            (frame(720, 480, anamorphic), none, 720, None),
+            (frame(720, 480, anamorphic), none, 360, Some("scale=640:360,setsar=1")),
+            // Scaled by the whole frame, not by the picture left after the crop
+            (frame(1920, 1080, 1.0), cropped(1920, 800, 0, 140), 720,
+                Some("crop=1920:800:0:140,scale=1280:534,setsar=1")),
+            (frame(1920, 1080, 1.0), cropped(1920, 800, 0, 140), 480,
+                Some("crop=1920:800:0:140,scale=854:356,setsar=1")),
+            (frame(1920, 1080, 1.0), cropped(1440, 1080, 240, 0), 1080,
+                Some("crop=1440:1080:240:0")),
+            // Odd results are rounded to the nearest even number
+            (frame(1918, 1080, 1.0), none, 720, Some("scale=1278:720,setsar=1")),
+            (frame(1000, 562, 1.0), none, 480, Some("scale=854:480,setsar=1")),
+            (frame(853, 480, 1.0), none, 360, Some("scale=640:360,setsar=1")),
+        ];
+
+        for (frame, analysis, height, expected) in cases {
+            let chain = chain(frame, &analysis, Deinterlacer::Bwdif, height);
+            assert_eq!(
+                chain.as_deref(),
+                expected,
+                "{frame:?} {analysis} at {height}"
+            );
+        }
+
+        let chain = |deinterlacer| chain(frame(720, 480, 1.0), &interlaced, deinterlacer, 720);
+
+        assert_eq!(
+            chain(Deinterlacer::Yadif).as_deref(),
+            Some("yadif=mode=send_frame:parity=bff")
+        );
+        assert_eq!(chain(Deinterlacer::Off), None);
+    }
+}
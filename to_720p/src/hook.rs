@@ -0,0 +1,111 @@
+//! User commands run when a video is done and when the batch is over. They are told about the
+//! result through `TO720P_*` environment variables.
+
+use anyhow::Result;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// How the batch ended, for `--on-finish`
+#[derive(Debug, Clone, Copy)]
+pub struct Summary {
+    pub done: usize,
+    pub failed: usize,
+    pub total: usize,
+    pub bytes_saved: i64,
+    pub cancelled: bool,
+}
+
+/// Run the `--on-file-done` hook for `input` and the result of its encode, giving back the bytes
+/// saved, negative when the output is bigger
+pub fn file_done(
+    command: Option<&str>,
+    input: &Path,
+    input_size: u64,
+    res: &Result<PathBuf>,
+) -> i64 {
+    let (status, output, error) = match res {
+        Ok(output) => ("done", Some(output), None),
+        Err(why) => ("failed", None, Some(format!("{why:#}"))),
+    };
+
+    let output_size = output.and_then(|v| fs::metadata(v).ok()).map(|v| v.len());
+    let bytes_saved = output_size.map_or(0, |v| input_size as i64 - v as i64);
+
+    let Some(command) = command else {
+        return bytes_saved;
+    };
+
+    let path = |v: Option<&PathBuf>| v.map(|v| v.display().to_string()).unwrap_or_default();
+
+    run(
+        command,
+        &[
+            ("TO720P_STATUS", status.to_owned()),
+            ("TO720P_INPUT", input.display().to_string()),
+            ("TO720P_OUTPUT", path(output)),
+            ("TO720P_INPUT_SIZE", input_size.to_string()),
+            (
+                "TO720P_OUTPUT_SIZE",
+                output_size.map(|v| v.to_string()).unwrap_or_default(),
+            ),
+            ("TO720P_BYTES_SAVED", bytes_saved.to_string()),
+            ("TO720P_ERROR", error.unwrap_or_default()),
+        ],
+    );
+
+    bytes_saved
+}
+
+/// Run the `--on-finish` hook
+pub fn finish(command: Option<&str>, summary: Summary) {
+    let Some(command) = command else {
+        return;
+    };
+
+    let status = if summary.cancelled {
+        "cancelled"
+    } else if summary.failed > 0 {
+        "failed"
+    } else {
+        "done"
+    };
+
+    run(
+        command,
+        &[
+            ("TO720P_STATUS", status.to_owned()),
+            ("TO720P_DONE", summary.done.to_string()),
+            ("TO720P_FAILED", summary.failed.to_string()),
+            ("TO720P_TOTAL", summary.total.to_string()),
+            ("TO720P_BYTES_SAVED", summary.bytes_saved.to_string()),
+        ],
+    );
+}
+
+/// Run `command` through the shell and wait for it. A failing hook is only reported, the
+/// videos are encoded already
+fn run(command: &str, vars: &[(&str, String)]) {
+    #[cfg(not(target_os = "windows"))]
+    let mut shell = {
+        let mut shell = Command::new("sh");
+        shell.arg("-c").arg(command);
+        shell
+    };
+
+    #[cfg(target_os = "windows")]
+    let mut shell = {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C").arg(command);
+        shell
+    };
+
+    shell.envs(vars.iter().map(|(k, v)| (k, v)));
+    log::debug!("Running the hook {command:?}");
+
+    match shell.status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => log::warn!("The hook {command:?} exited with {status}"),
+        Err(why) => log::warn!("Cannot run the hook {command:?}: {why}"),
+    }
+}
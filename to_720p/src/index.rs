@@ -0,0 +1,165 @@
+use crate::VideoMetadata;
+use anyhow::{Context as _, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Read as _, Seek as _, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+const INDEX_FILE: &str = "index.json";
+
+/// How much of the start and of the end of a file goes into its hash
+const HASHED_LEN: u64 = 64 * 1024;
+
+/// What happened to a video the last time it was looked at
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    /// Within the limits, left as it is
+    Fine,
+    /// Encoded by an earlier run, or the output of one
+    Encoded,
+    /// The encode failed with this error
+    Failed(String),
+}
+
+/// What tells whether a file is still the one that was indexed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Key {
+    size: u64,
+    modified: SystemTime,
+    /// Of the start and the end of the file, in case the file changed but kept its size and time
+    hash: u64,
+}
+
+impl Key {
+    pub fn of(path: &Path) -> Result<Self> {
+        let mut file = File::open(path)?;
+        let metadata = file.metadata()?;
+        let size = metadata.len();
+
+        let mut data = Vec::new();
+        file.by_ref().take(HASHED_LEN).read_to_end(&mut data)?;
+
+        if size > HASHED_LEN * 2 {
+            file.seek(SeekFrom::End(-(HASHED_LEN as i64)))?;
+            file.take(HASHED_LEN).read_to_end(&mut data)?;
+        }
+
+        Ok(Self {
+            size,
+            modified: metadata.modified()?,
+            hash: fnv1a(&data),
+        })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    key: Key,
+    pub metadata: VideoMetadata,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<Outcome>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated: Option<DateTime<Local>>,
+}
+
+/// Videos seen by earlier runs, so a rescan does not probe them again and does not retry the
+/// ones that failed
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Index {
+    #[serde(skip)]
+    path: PathBuf,
+    videos: BTreeMap<PathBuf, Entry>,
+}
+
+impl Index {
+    /// Where the index is kept unless `--index` says otherwise
+    pub fn default_path() -> PathBuf {
+        crate::queue::Queue::default_path().with_file_name(INDEX_FILE)
+    }
+
+    /// Load the index, an empty one when there is none yet. Videos that are gone are dropped
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+
+        let mut index: Self = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)
+                .with_context(|| format!("Cannot parse the index {:?}", path))?,
+            Err(why) if why.kind() == std::io::ErrorKind::NotFound => Self::default(),
+            Err(why) => {
+                return Err(why).with_context(|| format!("Cannot read the index {:?}", path))
+            }
+        };
+
+        index.videos.retain(|path, _| path.exists());
+        index.path = path;
+        Ok(index)
+    }
+
+    /// Write the index out, through a temporary file so a crash never leaves half of it
+    pub fn save(&self) -> Result<()> {
+        if macros::global().dry_run {
+            return Ok(());
+        }
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec(self)?)?;
+        fs::rename(&tmp, &self.path)
+            .with_context(|| format!("Cannot save the index {:?}", self.path))
+    }
+
+    /// The entry of the video at `path`, as long as it is still the same file
+    pub fn get(&self, path: &Path, key: &Key) -> Option<&Entry> {
+        self.videos.get(&canonical(path)).filter(|v| v.key == *key)
+    }
+
+    /// Remember the probe of a video, forgetting what happened to an older file at that path
+    pub fn insert(&mut self, path: &Path, key: Key, metadata: VideoMetadata) {
+        let entry = Entry {
+            key,
+            metadata,
+            outcome: None,
+            updated: Some(Local::now()),
+        };
+
+        self.videos.insert(canonical(path), entry);
+    }
+
+    /// Record what happened to an indexed video
+    pub fn set(&mut self, path: &Path, outcome: Outcome) {
+        if let Some(entry) = self.videos.get_mut(&canonical(path)) {
+            entry.outcome = Some(outcome);
+            entry.updated = Some(Local::now());
+        }
+    }
+
+    /// Drop the video at `path`, or every video under it for a folder, giving back how many
+    pub fn forget(&mut self, path: &Path) -> usize {
+        let path = canonical(path);
+        let before = self.videos.len();
+        self.videos.retain(|v, _| !v.starts_with(&path));
+        before - self.videos.len()
+    }
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// 64 bits FNV-1a, stable across Rust versions unlike the std hasher
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
@@ -1,9 +1,38 @@
-use anyhow::{Context as _, Error, Result};
-use clap::Parser;
+mod audio;
+mod container;
+mod encoder;
+mod filters;
+mod hook;
+mod index;
+mod native;
+mod oversize;
+mod progress;
+mod quality;
+mod queue;
+mod verify;
+
+use anyhow::{bail, Context as _, Error, Result};
+use clap::{Parser, Subcommand};
+use container::Container;
+use encoder::{Encoder, Kind, Pass, Rate};
+use ffmpeg_wrapper::{Ffmpeg, Probe};
+use filters::{Analysis, Deinterlacer, Frame};
+use hook::Summary;
+use index::{Index, Key, Outcome};
+use macros::cancel::{self, Cancelled, Partial};
+use macros::plan::Plan;
+use macros::report::Item;
+use oversize::{Codec, Limits, DEFAULT_FRAME_RATE};
+use progress::{Bars, FileBar};
+use quality::Score;
+use queue::{Queue, Status};
+use serde::{Deserialize, Serialize};
 use std::collections::LinkedList;
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
 use std::time::{Duration, Instant};
 
 #[cfg(target_os = "linux")]
@@ -11,8 +40,18 @@ use std::os::unix::fs::MetadataExt;
 #[cfg(target_os = "windows")]
 use std::os::windows::fs::MetadataExt;
 
-const SIZE_OFFSET: u64 = 300 * 1024 * 1024; // 300MB offset
-const SIZE_PER_SECOND: u64 = 300000; // cal base on a video with 898MB and 53m
+/// Assumed bitrate of an audio stream whose bitrate is unknown, the libopus one
+const AUDIO_BITRATE: u64 = 192_000;
+/// Share of a file taken by the container rather than the streams
+const CONTAINER_OVERHEAD: f64 = 0.02;
+/// Sizes are logged in decimal megabytes, the unit of `--target-size`
+const MB: u64 = 1_000_000;
+/// Below this a 720p video is not worth watching
+const MIN_BITRATE: u64 = 300_000;
+/// How many times a video is encoded again at a higher quality to reach `--min-ssim`
+const QUALITY_RETRIES: usize = 2;
+/// The command the deprecated `--shutdown` runs once all the videos are done
+const SHUTDOWN: &str = "shutdown";
 const SUPPORTED_EXT: &[&str] = &["mp4", "mkv", "avi", "ts", "wmv"];
 
 #[derive(Parser, Debug)]
@@ -36,14 +75,63 @@ pub struct Args {
     /// replace the original file
     replace: bool,
 
-    #[clap(short, long, default_value = "h264_nvenc")]
-    /// Chose the encoder for video
+    #[clap(short, long, default_value = "auto")]
+    /// Chose the encoder for video, `auto` picks the best one that works on this machine
     video: String,
 
+    #[clap(long, conflicts_with_all = ["bitrate", "target_size"])]
+    /// Constant quality level instead of the default of the encoder, lower is better. Mapped to
+    /// -crf, -cq or -qp depending on the encoder
+    crf: Option<u8>,
+
+    #[clap(long, value_parser = encoder::parse_bitrate, conflicts_with = "target_size")]
+    /// Average video bitrate, e.g. 2500k or 2.5M. k, M and G are 1000, 1000000 and 1000000000
+    /// bit/s like in ffmpeg
+    bitrate: Option<u64>,
+
+    #[clap(long, value_parser = encoder::parse_size)]
+    /// Aim for output files under this size, e.g. 700M or 1.5G. k, M and G are decimal like for
+    /// --bitrate, only KiB, MiB and GiB are binary. The video bitrate is computed from the
+    /// duration of each video
+    target_size: Option<u64>,
+
+    #[clap(long)]
+    /// Encoder preset instead of the default one, e.g. slow for libx264 or p7 for nvenc
+    preset: Option<String>,
+
+    #[clap(long)]
+    /// Encode in two passes for a more accurate bitrate, needs --bitrate or --target-size
+    two_pass: bool,
+
+    #[clap(long)]
+    /// Compare a few segments of each output with the source, and encode again at a higher
+    /// quality when their SSIM is below this, e.g. 0.95. The output is dropped when it stays
+    /// below
+    min_ssim: Option<f64>,
+
+    #[clap(long, default_value_t = 1)]
+    /// Number of videos encoded at the same time
+    jobs: usize,
+
+    #[clap(long, value_parser = encoder::parse_sessions)]
+    /// Cap --jobs for an encoder name or kind (nvenc, vaapi, software), e.g. nvenc=2. nvenc is
+    /// capped at 3 by default
+    encoder_jobs: Vec<(String, usize)>,
+
     #[clap(short, long, default_value = "copy")]
-    /// Chose the encoder for audio
+    /// Chose the encoder for audio. The tracks changed by --loudnorm or --stereo cannot be
+    /// copied, they are encoded with aac instead
     audio: String,
 
+    #[clap(long)]
+    /// Bring the loudness of every audio track to -23 LUFS (EBU R128), measured over the whole
+    /// track first
+    loudnorm: bool,
+
+    #[clap(long)]
+    /// Downmix the audio tracks with more than two channels to stereo
+    stereo: bool,
+
     #[clap(short, long, default_value = "auto")]
     /// Chose the frame rate for the output video
     frame_rate: String,
@@ -53,49 +141,513 @@ pub struct Args {
     ignore: Vec<String>,
 
     #[clap(long)]
-    /// force all the video to be an mp4 one
+    /// force all the video to be an mp4 one. MKV sources are kept as MKV otherwise, in MP4 the
+    /// text subtitles become mov_text and the image subtitles and attachments are dropped
     force_mp4: bool,
 
+    #[clap(long, value_parser = filters::parse_height, default_value = "720")]
+    /// Lines on the shorter side of the output, e.g. 480 or 1080p, or one of sd (480), hd (720)
+    /// and fhd (1080). Smaller videos keep their size
+    height: u32,
+
+    #[clap(long, alias = "force-720")]
+    /// Encode every video above --height, even the ones within the bits per pixel limits
+    force_height: bool,
+
     #[clap(long)]
-    /// force all the video to be 720p
-    force_720: bool,
+    /// Keep the black bars instead of cropping the ones found
+    no_crop: bool,
 
-    #[clap(short, long)]
-    /// shutdown after all the process done
+    #[clap(long, value_enum, default_value_t = Deinterlacer::Bwdif)]
+    /// Filter for the videos found interlaced, off to never look for interlacing
+    deinterlace: Deinterlacer,
+
+    #[clap(long, value_parser = oversize::parse_limit)]
+    /// Bits per pixel per frame above which a video is encoded again, per codec, e.g.
+    /// hevc=0.07. Defaults to h264=0.10, hevc=0.065, vp9=0.07, av1=0.05 and other=0.12
+    max_bpp: Vec<(Codec, f64)>,
+
+    #[clap(long)]
+    /// Print why each video was selected or not
+    explain: bool,
+
+    #[clap(long)]
+    /// Command run through the shell after each video, with TO720P_STATUS (done or failed),
+    /// TO720P_INPUT, TO720P_OUTPUT, TO720P_INPUT_SIZE, TO720P_OUTPUT_SIZE, TO720P_BYTES_SAVED and
+    /// TO720P_ERROR in its environment
+    on_file_done: Option<String>,
+
+    #[clap(long)]
+    /// Command run through the shell once all the videos are done, e.g. `systemctl suspend`, with
+    /// TO720P_STATUS (done, failed or cancelled), TO720P_DONE, TO720P_FAILED, TO720P_TOTAL and
+    /// TO720P_BYTES_SAVED in its environment
+    on_finish: Option<String>,
+
+    #[clap(short, long, hide = true, conflicts_with = "on_finish")]
+    /// Deprecated, the same as `--on-finish shutdown` except that a cancelled run is not powered
+    /// off
     shutdown: bool,
+
+    #[clap(long)]
+    /// Continue the queue of the last run instead of scanning again. The encoding options are
+    /// still taken from the command line
+    resume: bool,
+
+    #[clap(long, global = true)]
+    /// Where to keep the work list of the run, default to one in the tmoutils data directory
+    queue: Option<PathBuf>,
+
+    #[clap(long)]
+    /// Try again the videos whose encode failed in an earlier run, they are skipped otherwise
+    retry_failed: bool,
+
+    #[clap(long)]
+    /// Drop a video, or every video in a folder, from the index of the videos seen before, then
+    /// exit
+    forget: Vec<PathBuf>,
+
+    #[clap(long, global = true)]
+    /// Where to keep the index of the videos seen before, default to one in the tmoutils data
+    /// directory
+    index: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    action: Option<Action>,
+}
+
+/// What the workers of a batch share
+struct Batch {
+    bars: Bars,
+    queue: Mutex<Queue>,
+    /// Paths encoded successfully
+    finished: Mutex<Vec<PathBuf>>,
+    failed: AtomicUsize,
+    /// By the videos encoded so far, negative when they grew
+    bytes_saved: AtomicI64,
+    index: Mutex<Index>,
+    count: usize,
+    encoder: Encoder,
+}
+
+#[derive(Subcommand, Debug)]
+enum Action {
+    /// Show the state of the queue
+    Status,
 }
 
 impl Args {
     pub fn exec(&self) -> Result<()> {
+        if let Some(Action::Status) = self.action {
+            Queue::load(self.queue_path())?.print_status();
+            return Ok(());
+        }
+
+        if self.shutdown {
+            log::warn!("--shutdown is deprecated, use --on-finish {SHUTDOWN:?} instead");
+        }
+
+        let mut index = Index::load(self.index_path())?;
+
+        if !self.forget.is_empty() {
+            for path in &self.forget {
+                log::info!("Forgot {} videos in {:?}", index.forget(path), path);
+            }
+
+            return index.save();
+        }
+
+        if self.two_pass && self.bitrate.is_none() && self.target_size.is_none() {
+            bail!("--two-pass needs either --bitrate or --target-size");
+        }
+
+        if self.min_ssim.is_some_and(|v| !(0.0..=1.0).contains(&v)) {
+            bail!("--min-ssim has to be between 0 and 1");
+        }
+
         let path = PathBuf::from(&self.path);
-        let output_dir = PathBuf::from(&self.output_dir);
 
         if path.is_file() {
-            let video = Video::from_path(&path)?;
-
-            if video.is_over_sized() {
-                downscale(
-                    &video,
-                    &output_dir,
-                    &self.video,
-                    &self.audio,
-                    &self.frame_rate,
-                    self.replace,
-                )?;
+            let video = Video::from_path(&path, &mut index)?;
+            let (selected, reason) = self.assess(&video, &Limits::new(&self.max_bpp));
+
+            if self.explain {
+                log::info!("{:?}: {reason}", video.path);
+            }
+
+            if selected && macros::global().dry_run {
+                println!("[{:<7}] {}", "encode", video.path.display());
+            } else if selected {
+                let encoder = self.encoder()?;
+                let mut item = Item::new("encode", &video.path);
+                let bars = Bars::new(video.metadata.duration, 1, 1);
+                let mut bar = bars.file(&video.name(), video.metadata.duration);
+                let res = self.downscale(&video, &encoder, &mut bar, &mut item);
+                drop(bar);
+                bars.finish();
+
+                record(&mut index, &video, &res)?;
+
+                let cancelled = cancel::is_cancelled();
+                let bytes_saved = match cancelled {
+                    true => 0,
+                    false => {
+                        hook::file_done(self.on_file_done.as_deref(), &video.path, video.size, &res)
+                    }
+                };
+
+                let summary = Summary {
+                    done: res.is_ok() as usize,
+                    failed: (res.is_err() && !cancelled) as usize,
+                    total: 1,
+                    bytes_saved,
+                    cancelled,
+                };
+
+                hook::finish(self.on_finish(summary.cancelled), summary);
+
+                match res {
+                    Ok(output) => item.output(output).succeeded(),
+                    Err(why) => {
+                        item.failed(&why);
+                        return Err(why);
+                    }
+                }
             } else {
-                panic!("The video looks fine");
+                index.set(&video.path, Outcome::Fine);
+                index.save()?;
+                Item::new("encode", &video.path).skipped("The video looks fine");
             }
 
-            if self.shutdown {
-                Command::new("shutdown").spawn()?.wait()?;
+            return Ok(());
+        }
+
+        let queue = match self.resume {
+            true => {
+                let queue = Queue::load(self.queue_path())?;
+                let remaining = queue.remaining().count();
+                log::info!(
+                    "Resuming {:?}, {remaining} of {} left",
+                    queue.path(),
+                    queue.jobs.len()
+                );
+                queue
+            }
+            false => {
+                let list = self.select(&path, &mut index)?;
+                Queue::new(self.queue_path(), list.into_iter().map(|v| v.path))
             }
+        };
 
+        if macros::global().dry_run {
+            queue.print_status();
             return Ok(());
         }
 
+        queue.save()?;
+
+        let encoder = match queue.remaining().next() {
+            Some(_) => self.encoder()?,
+            None => {
+                log::info!("Nothing left in the queue");
+                return Ok(());
+            }
+        };
+
+        log::info!("Processing");
+
+        let start = Instant::now();
+        let jobs = queue.remaining().collect::<Vec<_>>();
+
+        let videos = jobs
+            .iter()
+            .map(|&i| Video::from_path(&queue.jobs[i].path, &mut index))
+            .collect::<Vec<_>>();
+
+        let total = videos.iter().flatten().map(|v| v.metadata.duration).sum();
+        let workers = self.workers(&encoder, jobs.len());
+
+        let batch = Batch {
+            bars: Bars::new(total, jobs.len(), workers),
+            queue: Mutex::new(queue),
+            finished: Mutex::new(Vec::new()),
+            failed: AtomicUsize::new(0),
+            bytes_saved: AtomicI64::new(0),
+            index: Mutex::new(index),
+            count: jobs.len(),
+            encoder,
+        };
+
+        // Handed out in the order of the queue, so the worst videos still go first
+        let pending = Mutex::new(jobs.into_iter().zip(videos).zip(1..));
+
+        let res: Result<()> = thread::scope(|scope| {
+            let workers = (0..workers)
+                .map(|_| {
+                    scope.spawn(|| loop {
+                        if cancel::is_cancelled() {
+                            return Ok(());
+                        }
+
+                        let Some(((index, video), i)) = pending.lock().unwrap().next() else {
+                            return Ok(());
+                        };
+
+                        self.process(&batch, index, video, i)?;
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            workers
+                .into_iter()
+                .try_for_each(|v| v.join().expect("worker panicked"))
+        });
+
+        batch.bars.finish();
+        res?;
+
+        let summary = Summary {
+            done: batch.finished.lock().unwrap().len(),
+            failed: batch.failed.load(Ordering::Relaxed),
+            total: batch.count,
+            bytes_saved: batch.bytes_saved.load(Ordering::Relaxed),
+            cancelled: cancel::is_cancelled(),
+        };
+
+        hook::finish(self.on_finish(summary.cancelled), summary);
+
+        if summary.cancelled {
+            cancel::summary(&batch.finished.lock().unwrap(), batch.count);
+            return Err(Cancelled.into());
+        }
+
+        log::info!(
+            "Done! All of them took {}",
+            humantime::format_duration(start.elapsed())
+        );
+
+        Ok(())
+    }
+
+    /// Encode one video of the batch and record the outcome in the queue
+    fn process(&self, batch: &Batch, index: usize, video: Result<Video>, i: usize) -> Result<()> {
+        let path = batch.queue.lock().unwrap().jobs[index].path.clone();
+        let mut item = Item::new("encode", &path);
+        let set = |status, error| batch.queue.lock().unwrap().set(index, status, error);
+
+        let video = match video {
+            Ok(v) => v,
+            Err(why) => {
+                batch.bars.suspend(|| log::error!("{:?}: {:#}", path, why));
+                set(Status::Failed, Some(format!("{why:#}")))?;
+                batch.failed.fetch_add(1, Ordering::Relaxed);
+                item.failed(why);
+                return Ok(());
+            }
+        };
+
+        batch.bars.suspend(|| {
+            log::info!(
+                "({i}/{}) {}x{} ({}p) {} minutes {}MB - {:?}",
+                batch.count,
+                video.metadata.width,
+                video.metadata.height,
+                video.resolution(),
+                video.metadata.duration.as_secs() / 60,
+                video.size / MB,
+                video.path
+            )
+        });
+
+        let video_start = Instant::now();
+        set(Status::Running, None)?;
+
+        let mut bar = batch.bars.file(&video.name(), video.metadata.duration);
+        let res = self.downscale(&video, &batch.encoder, &mut bar, &mut item);
+        drop(bar);
+
+        if !cancel::is_cancelled() {
+            record(&mut batch.index.lock().unwrap(), &video, &res)?;
+
+            let on_file_done = self.on_file_done.as_deref();
+            let bytes_saved = batch
+                .bars
+                .suspend(|| hook::file_done(on_file_done, &video.path, video.size, &res));
+            batch.bytes_saved.fetch_add(bytes_saved, Ordering::Relaxed);
+        }
+
+        match res {
+            Ok(output) => {
+                batch.bars.suspend(|| {
+                    log::info!(
+                        "Done! {:?} took {}",
+                        video.path,
+                        humantime::format_duration(video_start.elapsed())
+                    )
+                });
+                set(Status::Done, None)?;
+                item.output(output).succeeded();
+                batch.finished.lock().unwrap().push(path);
+            }
+            Err(_) if cancel::is_cancelled() => {
+                set(Status::Pending, None)?;
+                item.failed(Cancelled);
+            }
+            Err(why) => {
+                batch
+                    .bars
+                    .suspend(|| log::error!("{:?}: {:#?}", video.path, why));
+                set(Status::Failed, Some(format!("{why:#}")))?;
+                batch.failed.fetch_add(1, Ordering::Relaxed);
+                item.failed(why);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// How many videos to encode at once, within the limit of the encoder
+    fn workers(&self, encoder: &Encoder, jobs: usize) -> usize {
+        let mut workers = self.jobs.clamp(1, jobs.max(1));
+
+        if let Some(limit) = encoder.max_sessions(&self.encoder_jobs) {
+            if limit < workers {
+                log::info!("{} runs at most {limit} encodes at once", encoder.name);
+                workers = limit;
+            }
+        }
+
+        if workers > 1 {
+            log::info!("Encoding {workers} videos at once");
+        }
+
+        workers
+    }
+
+    fn queue_path(&self) -> PathBuf {
+        self.queue.clone().unwrap_or_else(Queue::default_path)
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.index.clone().unwrap_or_else(Index::default_path)
+    }
+
+    /// The `--on-finish` hook, the deprecated `--shutdown` does not power off a cancelled run
+    fn on_finish(&self, cancelled: bool) -> Option<&str> {
+        match self.shutdown {
+            true => (!cancelled).then_some(SHUTDOWN),
+            false => self.on_finish.as_deref(),
+        }
+    }
+
+    fn encoder(&self) -> Result<Encoder> {
+        let mut encoder = match self.video.as_str() {
+            "auto" => Encoder::detect()?,
+            name => {
+                log::info!("Using {name} as asked");
+                Encoder::from_name(name)
+            }
+        };
+
+        if let Some(ref preset) = self.preset {
+            encoder.preset = Some(preset.clone());
+        }
+
+        if let Some(crf) = self.crf {
+            encoder.rate = Some(Rate::Quality(crf));
+        }
+
+        if let Some(bitrate) = self.bitrate {
+            encoder.rate = Some(Rate::Bitrate(bitrate));
+        }
+
+        encoder.two_pass = self.two_pass;
+
+        if self.two_pass && !encoder.separate_passes() && encoder.kind != Kind::Nvenc {
+            log::warn!("{} cannot do two passes, using a single one", encoder.name);
+        }
+
+        Ok(encoder)
+    }
+
+    /// The video bitrate that makes the output land under `--target-size`
+    fn target_bitrate(&self, video: &Video, input: &Probe, target_size: u64) -> Result<u64> {
+        let seconds = video.metadata.duration.as_secs_f64();
+
+        if seconds < 1.0 {
+            bail!("The video is too short to aim for a size");
+        }
+
+        let audio = input
+            .audios()
+            .map(|v| audio::bitrate(v, &self.audio, self.loudnorm, self.stereo))
+            .map(|v| v.unwrap_or(AUDIO_BITRATE))
+            .sum::<u64>();
+
+        let total = (target_size as f64 * 8.0 * (1.0 - CONTAINER_OVERHEAD) / seconds) as u64;
+
+        let Some(bitrate) = total.checked_sub(audio).filter(|&v| v >= MIN_BITRATE) else {
+            bail!(
+                "{}MB is too small for {} minutes of video",
+                target_size / MB,
+                video.metadata.duration.as_secs() / 60
+            );
+        };
+
+        log::info!(
+            "Aiming for {}MB, video bitrate {}kbit/s, audio {}kbit/s",
+            target_size / MB,
+            bitrate / 1000,
+            audio / 1000
+        );
+
+        Ok(bitrate)
+    }
+
+    /// Whether the video should be encoded again, and why
+    fn assess(&self, video: &Video, limits: &Limits) -> (bool, String) {
+        let codec = video.metadata.codec;
+        let limit = limits.get(codec);
+        let bpp = video.bpp();
+
+        let mut reason = format!(
+            "{codec} {}x{} at {:.2} fps, {} kbit/s of video, {bpp:.3} bits per pixel",
+            video.metadata.width,
+            video.metadata.height,
+            video.frame_rate(),
+            video.video_bitrate() / 1000,
+        );
+
+        let over_sized = bpp > limit;
+        let forced_height = self.force_height && video.resolution() > self.height;
+        let forced_mp4 = self.force_mp4 && video.ext != "mp4";
+
+        match over_sized {
+            true => reason += &format!(", over the {codec} limit of {limit}"),
+            false => reason += &format!(", within the {codec} limit of {limit}"),
+        }
+
+        if forced_height {
+            reason += &format!(", {}p with --force-height", video.resolution());
+        }
+
+        if forced_mp4 {
+            reason += &format!(", {} with --force-mp4", video.ext);
+        }
+
+        let selected = over_sized || forced_height || forced_mp4;
+        let verdict = if selected { "selected" } else { "skipped" };
+
+        (selected, format!("{verdict}, {reason}"))
+    }
+
+    /// Scan for the videos that need to be processed and pick the worst of them, worst first
+    fn select(&self, path: &Path, index: &mut Index) -> Result<Vec<Video>> {
         log::info!("Loading videos");
 
-        let mut iter = Videos::new(&path, &self.ignore, self.depth)?
+        let limits = Limits::new(&self.max_bpp);
+        let mut fine = Vec::new();
+
+        let mut iter = Videos::new(path, &self.ignore, self.depth, index)?
             .inspect(|v| {
                 log::info!(
                     "{} {}x{} ({}p) {} minutes {} MB - {:?}",
@@ -104,14 +656,29 @@ impl Args {
                     v.metadata.height,
                     v.resolution(),
                     v.metadata.duration.as_secs() / 60,
-                    v.size / 1024 / 1024,
+                    v.size / MB,
                     v.path
                 )
             })
             .filter(|v| {
-                let check_720 = self.force_720 && v.resolution() > 720;
-                let check_mp4 = self.force_mp4 && v.ext != "mp4";
-                check_720 || check_mp4 || v.is_over_sized()
+                let (selected, reason) = match v.previous {
+                    Some(Outcome::Encoded) => (false, "skipped, encoded by an earlier run".into()),
+                    Some(Outcome::Failed(ref why)) if !self.retry_failed => (
+                        false,
+                        format!("skipped, failed in an earlier run, see --retry-failed\n{why}"),
+                    ),
+                    _ => self.assess(v, &limits),
+                };
+
+                if self.explain {
+                    log::info!("{:?}: {reason}", v.path);
+                }
+
+                if !selected && matches!(v.previous, None | Some(Outcome::Fine)) {
+                    fine.push(v.path.clone());
+                }
+
+                selected
             });
 
         let mut list = iter
@@ -120,27 +687,29 @@ impl Args {
             .collect::<LinkedList<_>>();
         let mut total = list.len();
 
-        let mut min_size_per_sec = list
-            .iter()
-            .map(|v| v.size_per_second())
-            .min()
-            .context("The list is empty")?;
+        let min_excess = list.iter().map(|v| v.excess(&limits)).reduce(f64::min);
+
+        let Some(mut min_excess) = min_excess else {
+            drop(iter);
+            record_fine(index, &fine)?;
+            bail!("The list is empty");
+        };
 
         // take the remaining video in the iterator
         for video in iter {
             total += 1;
-            let size_per_sec = video.size_per_second();
+            let excess = video.excess(&limits);
 
-            if size_per_sec <= min_size_per_sec {
+            if excess <= min_excess {
                 continue;
             }
 
-            let mut new_min = size_per_sec;
+            let mut new_min = excess;
             let mut swapped = false;
             for item in list.iter_mut() {
-                let size = item.size_per_second();
-                if swapped || size != min_size_per_sec {
-                    new_min = std::cmp::min(size, new_min);
+                let item_excess = item.excess(&limits);
+                if swapped || item_excess != min_excess {
+                    new_min = new_min.min(item_excess);
                     continue;
                 }
 
@@ -148,150 +717,344 @@ impl Args {
                 swapped = true;
             }
 
-            min_size_per_sec = new_min;
+            min_excess = new_min;
         }
 
+        record_fine(index, &fine)?;
+
         log::info!("Found {} videos need to process", total);
         log::info!("Taking first {}", list.len());
-        log::info!("Sorting");
 
-        log::info!("Processing");
+        let mut list = list.into_iter().collect::<Vec<_>>();
+        list.sort_by(|a, b| b.excess(&limits).total_cmp(&a.excess(&limits)));
 
-        let start = Instant::now();
-        for (video, i) in list.iter().zip(1..) {
+        Ok(list)
+    }
+
+    /// Run every pass of the encoder over the video, `filter` being the filter options of the
+    /// encoder
+    fn encode(
+        &self,
+        video: &Video,
+        encoder: &Encoder,
+        filter: &[String],
+        args: &[String],
+        output: &Path,
+        bar: &mut FileBar,
+    ) -> Result<()> {
+        let passlog = PassLog(output.with_extension("passlog"));
+        let passes = encoder.passes();
+        bar.set_passes(passes.len());
+
+        for (n, &pass) in passes.iter().enumerate() {
+            let ffmpeg = encoder.input_args(Ffmpeg::new().echo(bar.echo()));
+            let ffmpeg = ffmpeg.input(&video.path);
+            let mut ffmpeg = ffmpeg
+                .args(filter)
+                .args(encoder.args(pass, &passlog.0))
+                .cancel_when(cancel::is_cancelled);
+
+            ffmpeg = match pass {
+                Pass::First => ffmpeg
+                    .args(["-an", "-loglevel", "warning", "-nostats", "-f", "null"])
+                    .output("-"),
+                Pass::Only | Pass::Second => ffmpeg.args(args).output(output),
+            };
+
+            bar.suspend(|| log::info!("Executing command\n{:?}", ffmpeg.command()));
+
+            ffmpeg
+                .run_with_progress(|progress| bar.update(n, &progress))
+                .with_context(|| format!("Cannot convert {:?}", video.path))?;
+        }
+
+        Ok(())
+    }
+
+    /// How close the encode in `output` looks to the video, recorded in the report
+    fn score(
+        &self,
+        video: &Video,
+        filter: Option<&str>,
+        output: &Path,
+        bar: &FileBar,
+        item: &mut Item,
+    ) -> Result<Score> {
+        // The source goes through what the output went through, down to the frame rate
+        let fps = match self.frame_rate.as_str() {
+            "" | "auto" => None,
+            rate => Some(format!("fps={rate}")),
+        };
+
+        let reference = [filter.map(String::from), fps]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        let reference = Some(reference.join(",")).filter(|v| !v.is_empty());
+
+        let score = quality::score(
+            &video.path,
+            output,
+            video.metadata.duration,
+            reference.as_deref(),
+        )
+        .context("Cannot compare the output with the source")?;
+
+        item.set_metric("ssim", score.ssim);
+        item.set_metric("psnr", score.psnr);
+
+        bar.suspend(|| {
             log::info!(
-                "({i}/{}) {}x{} ({}p) {} minutes {}MB - {:?}",
-                list.len(),
-                video.metadata.width,
-                video.metadata.height,
-                video.resolution(),
-                video.metadata.duration.as_secs() / 60,
-                video.size / 1024 / 1024,
+                "SSIM {:.4}, PSNR {:.2}dB - {:?}",
+                score.ssim,
+                score.psnr,
                 video.path
-            );
+            )
+        });
+
+        Ok(score)
+    }
 
-            let video_start = Instant::now();
-            match downscale(
-                &video,
-                &output_dir,
-                &self.video,
-                &self.audio,
-                &self.frame_rate,
-                self.replace,
-            ) {
-                Ok(_) => log::info!(
-                    "Done! This video took {}",
-                    humantime::format_duration(video_start.elapsed())
-                ),
-                Err(why) => log::error!("{:#?}", why),
+    /// Where the black bars are and whether the video is interlaced. A failed analysis only
+    /// costs the crop and the deinterlacing, so the video is encoded as it is
+    fn analyze(&self, video: &Video, frame: Frame, bar: &FileBar) -> Result<Analysis> {
+        let dimensions = (frame.width, frame.height);
+        let crop = !self.no_crop;
+        let interlace = self.deinterlace != Deinterlacer::Off;
+
+        let analysis = match filters::analyze(
+            &video.path,
+            dimensions,
+            video.metadata.duration,
+            crop,
+            interlace,
+        ) {
+            Ok(analysis) => analysis,
+            Err(_) if cancel::is_cancelled() => return Err(Cancelled.into()),
+            Err(why) => {
+                bar.suspend(|| log::warn!("Encoding {:?} as it is: {:#}", video.path, why));
+                return Ok(Analysis::default());
             }
+        };
+
+        if crop || interlace {
+            bar.suspend(|| log::info!("Analyzed {:?}: {}", video.path, analysis));
         }
 
-        log::info!(
-            "Done! All of them took {}",
-            humantime::format_duration(start.elapsed())
+        Ok(analysis)
+    }
+
+    fn downscale(
+        &self,
+        video: &Video,
+        encoder: &Encoder,
+        bar: &mut FileBar,
+        item: &mut Item,
+    ) -> Result<PathBuf> {
+        let container = Container::for_input(video.ext, self.force_mp4);
+        let file_name = format!(
+            "{}.{}",
+            video.path.file_stem().unwrap().to_str().unwrap(),
+            container.ext()
         );
+        let output = Path::new(&self.output_dir).join(&file_name);
+
+        // MKV sources keep their extension, so an output directory holding the sources would
+        // make the output the video itself
+        if output.canonicalize().ok() == Some(video.path.canonicalize()?) {
+            bail!(
+                "The output {:?} is the video itself, choose another --output-dir",
+                output
+            );
+        }
 
-        if self.shutdown {
-            Command::new("shutdown").spawn()?.wait()?;
+        // Written under a temporary name, a file already at the output is never touched
+        let partial = Partial::new(output)?;
+
+        let input = ffmpeg_wrapper::probe(&video.path)?;
+        let (mapping, expected) = container.mapping(&input);
+
+        let mut encoder = encoder.clone();
+
+        if let Some(target_size) = self.target_size {
+            encoder.rate = Some(Rate::Bitrate(self.target_bitrate(
+                video,
+                &input,
+                target_size,
+            )?));
         }
 
-        Ok(())
-    }
-}
+        if self.loudnorm {
+            bar.suspend(|| log::info!("Measuring the loudness of {:?}", video.path));
+        }
 
-#[rustfmt::skip]
-fn downscale(video: &Video, output_dir: &Path, cv: &str, ca: &str, frame_rate: &str, replace: bool) -> Result<()> {
-    let file_name = format!("{}.mp4", video.path.file_stem().unwrap().to_str().unwrap());
-    let output = output_dir.join(&file_name);
-    let mut command = Command::new("ffmpeg");
+        let mut args = mapping;
+        args.extend(audio::args(
+            &video.path,
+            &input,
+            &self.audio,
+            self.loudnorm,
+            self.stereo,
+        )?);
+        args.extend(["-loglevel", "warning", "-nostats"].map(String::from));
+
+        if !self.frame_rate.is_empty() && self.frame_rate != "auto" {
+            args.extend(["-r".to_owned(), self.frame_rate.clone()]);
+        }
 
-    let mut filters = vec![
-        "-c:v", cv,
-        "-c:a", ca,
-        "-loglevel", "warning", "-hide_banner", "-stats"
-    ];
+        let frame = input
+            .video()
+            .and_then(Frame::from_stream)
+            .context("Unknown size of the video frames")?;
+        let analysis = self.analyze(video, frame, bar)?;
+        let filter = filters::chain(frame, &analysis, self.deinterlace, self.height);
+        let mut retries = 0;
 
-    if ca == "libopus" {
-        filters.extend(["-b:a", "192K"]);
-    }
+        loop {
+            self.encode(
+                video,
+                &encoder,
+                &encoder.filter_args(filter.as_deref(), expected.videos),
+                &args,
+                partial.path(),
+                bar,
+            )?;
+
+            let Some(min_ssim) = self.min_ssim else {
+                break;
+            };
+
+            let score = self.score(video, filter.as_deref(), partial.path(), bar, item)?;
+
+            if score.ssim >= min_ssim {
+                break;
+            }
 
-    if !frame_rate.is_empty() && frame_rate != "auto" {
-        filters.extend(["-r", frame_rate]);
-    }
+            // A higher bitrate would miss the target size
+            let better = match self.target_size {
+                Some(_) => None,
+                None => encoder
+                    .higher_quality()
+                    .filter(|_| retries < QUALITY_RETRIES),
+            };
+
+            let Some(better) = better else {
+                bail!(
+                    "Dropped the output, its SSIM of {:.4} is below --min-ssim {min_ssim}",
+                    score.ssim
+                );
+            };
+
+            if let Some(rate) = better.rate {
+                bar.suspend(|| {
+                    log::warn!(
+                        "SSIM of {:.4} is below --min-ssim {min_ssim}, encoding {:?} again at {rate}",
+                        score.ssim,
+                        video.path
+                    )
+                });
+            }
 
-    if let Some(filter) = video.vf_filter() {
-        filters.extend(["-vf", filter]);
-    }
+            fs::remove_file(partial.path())?;
+            encoder = better;
+            retries += 1;
+        }
 
-    command
-        .arg("-i")
-        .arg(&video.path)
-        .args(filters)
-        .arg(&output)
-        .stdin(Stdio::null());
+        let output = partial.finish()?;
 
-    log::info!("Executing command\n{:?}", &command);
+        let old_size: i64;
+        let new_size: i64;
 
-    let status = command.spawn()?.wait()?;
+        #[cfg(target_os = "linux")]
+        {
+            old_size = video.path.metadata()?.size() as i64;
+            new_size = output.metadata()?.size() as i64;
+        }
 
-    if !status.success() {
-        return Err(Error::msg("Something went wrong"));
-    }
+        #[cfg(target_os = "windows")]
+        {
+            old_size = video.path.metadata()?.file_size() as i64;
+            new_size = output.metadata()?.file_size() as i64;
+        }
 
-    let old_size: i64;
-    let new_size: i64;
+        bar.suspend(|| {
+            log::info!(
+                "Done {:?}\nOutput {:?}\nNew size {}MB (reduced {}MB)",
+                video.path,
+                output,
+                new_size / MB as i64,
+                (old_size - new_size) / MB as i64,
+            )
+        });
+
+        if self.replace && old_size > new_size {
+            if let Err(why) = verify::verify(&input, &output, expected) {
+                return Err(why.context(format!(
+                    "Kept the original, the output {:?} failed the verification",
+                    output
+                )));
+            }
 
-    #[cfg(target_os = "linux")]
-    {
-        old_size = video.path.metadata()?.size() as i64;
-        new_size = output.metadata()?.size() as i64;
-    }
+            bar.suspend(|| log::info!("Verified {:?}", output));
 
-    #[cfg(target_os = "windows")]
-    {
-        old_size = video.path.metadata()?.file_size() as i64;
-        new_size = output.metadata()?.file_size() as i64;
-    }
+            let new_file_path = video.path.canonicalize()?.parent().unwrap().join(file_name);
+            let mut plan = Plan::new();
+            plan.delete(&video.path);
+            plan.rename(&output, &new_file_path);
+            plan.execute()?;
+            return Ok(new_file_path);
+        }
 
-    log::info!(
-        "Done {:?}\nOutput {:?}\nNew size {}MB (reduced {}MB)",
-        video.path,
-        output,
-        new_size / 1024 / 1024,
-        (old_size - new_size) / 1024 / 1024,
-    );
-
-    if replace && old_size > new_size {
-        let new_file_path = video.path.canonicalize()?.parent().unwrap().join(file_name);
-        fs::remove_file(&video.path).ok();
-        move_file(&output, &new_file_path)?;
+        Ok(output)
     }
-
-    Ok(())
 }
 
-#[cfg(target_os = "linux")]
-fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
-    let dev_a = fs::metadata(from)?.dev();
-    let dev_b = fs::metadata(to)?.dev();
+/// Remember in the index how the encode of `video` went
+fn record(index: &mut Index, video: &Video, res: &Result<PathBuf>) -> Result<()> {
+    match res {
+        Ok(output) => {
+            // The output too, so a later scan does not take it for a new video
+            if Video::from_path(output, index).is_ok() {
+                index.set(output, Outcome::Encoded);
+            }
 
-    fs::remove_file(to).ok();
+            index.set(&video.path, Outcome::Encoded);
+        }
+        Err(why) => index.set(&video.path, Outcome::Failed(format!("{why:#}"))),
+    }
 
-    if dev_a == dev_b {
-        fs::rename(from, to)?;
-    } else {
-        fs::copy(from, to)?;
-        fs::remove_file(from)?;
+    index.save()
+}
+
+/// Remember in the index the videos that were found within the limits
+fn record_fine(index: &mut Index, paths: &[PathBuf]) -> Result<()> {
+    for path in paths {
+        index.set(path, Outcome::Fine);
     }
 
-    Ok(())
+    index.save()
 }
 
-#[cfg(target_os = "windows")]
-fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
-    fs::remove_file(to).ok();
-    fs::rename(from, to)
+/// Statistics files of a two-pass encode, removed once the encode is over
+struct PassLog(PathBuf);
+
+impl Drop for PassLog {
+    fn drop(&mut self) {
+        let (Some(dir), Some(prefix)) = (self.0.parent(), self.0.file_name()) else {
+            return;
+        };
+
+        let prefix = prefix.to_string_lossy();
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+
+        for entry in entries.flatten() {
+            if entry.file_name().to_string_lossy().starts_with(&*prefix) {
+                fs::remove_file(entry.path()).ok();
+            }
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -300,103 +1063,137 @@ struct Video {
     ext: &'static str,
     size: u64,
     path: PathBuf,
+    /// What happened to it in an earlier run
+    previous: Option<Outcome>,
 }
 
 impl Video {
-    fn from_path(p: &Path) -> Result<Self> {
+    /// Read the metadata of the video, or take it from the index when the file did not change
+    fn from_path(p: &Path, index: &mut Index) -> Result<Self> {
         let ext = p.extension().context("No extension")?.to_ascii_lowercase();
         let ext = *SUPPORTED_EXT
-            .into_iter()
+            .iter()
             .find(|&&v| v == ext)
             .context("File extension is not supported yet")?;
 
-        let size = fs::metadata(&p)?.len();
+        let key = Key::of(p)?;
+        let size = key.size();
+
+        if let Some(entry) = index.get(p, &key) {
+            return Ok(Self {
+                path: p.to_path_buf(),
+                size,
+                ext,
+                metadata: entry.metadata,
+                previous: entry.outcome.clone(),
+            });
+        }
+
         let metadata_opt = match ext {
-            "mp4" => VideoMetadata::mp4(&p),
-            "mkv" => VideoMetadata::mkv(&p),
+            "mp4" => VideoMetadata::mp4(p),
+            "mkv" => VideoMetadata::mkv(p),
+            "avi" => native::avi::read(p),
+            "ts" => native::ts::read(p),
+            "wmv" => native::asf::read(p),
             _ => Err(Error::msg("Need ffmpeg")),
         };
 
-        let metadata = metadata_opt.or_else(|_| VideoMetadata::ffprobe(&p))?;
+        let metadata = metadata_opt.or_else(|_| VideoMetadata::ffprobe(p))?;
+        index.insert(p, key, metadata);
 
         Ok(Self {
             path: p.to_path_buf(),
             size,
             ext,
             metadata,
+            previous: None,
         })
     }
 
+    /// File name for the progress bar
+    fn name(&self) -> String {
+        self.path
+            .file_name()
+            .map(|v| v.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    /// Shorter side of the stored frames
     fn resolution(&self) -> u32 {
         std::cmp::min(self.metadata.width, self.metadata.height)
     }
 
-    fn vf_filter(&self) -> Option<&'static str> {
-        if self.resolution() <= 720 {
-            return None;
-        }
+    fn frame_rate(&self) -> f64 {
+        self.metadata.frame_rate.unwrap_or(DEFAULT_FRAME_RATE)
+    }
 
-        if self.metadata.width > self.metadata.height {
-            Some("scale=-1:720")
-        } else {
-            Some("scale=720:-1")
-        }
+    /// Average bitrate of the file without an estimate of the audio, in bits per second
+    fn video_bitrate(&self) -> u64 {
+        let seconds = self.metadata.duration.as_secs_f64().max(1.0);
+        let bitrate = (self.size as f64 * 8.0 / seconds) as u64;
+        bitrate.saturating_sub(AUDIO_BITRATE)
     }
 
-    fn is_over_sized(&self) -> bool {
-        self.size > self.metadata.duration.as_secs() * SIZE_PER_SECOND + SIZE_OFFSET
+    /// Bits spent on each pixel of each frame
+    fn bpp(&self) -> f64 {
+        let pixels = self.metadata.width as f64 * self.metadata.height as f64;
+        self.video_bitrate() as f64 / (pixels * self.frame_rate()).max(1.0)
     }
 
-    fn size_per_second(&self) -> u64 {
-        self.size / self.metadata.duration.as_secs()
+    /// How far over the limit of its codec the video is, above 1 means over sized
+    fn excess(&self, limits: &Limits) -> f64 {
+        self.bpp() / limits.get(self.metadata.codec)
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 struct VideoMetadata {
     height: u32,
     width: u32,
     duration: Duration,
+    frame_rate: Option<f64>,
+    codec: Codec,
 }
 
 impl VideoMetadata {
     fn ffprobe(p: &Path) -> Result<Self> {
-        let cmd = Command::new("ffprobe")
-            .args(["-v", "error"])
-            .args(["-select_streams", "v"])
-            .args(["-show_entries", "stream=width,height,duration"])
-            .args(["-of", "csv=p=0:s=x"])
-            .arg(&p)
-            .output()?;
-
-        let mut iter = std::str::from_utf8(&cmd.stdout)?.trim_end().split("x");
-        let width = iter.next().context("Get video width")?.parse::<u32>()?;
-        let height = iter.next().context("Get video height")?.parse::<u32>()?;
-        let duration = iter.next().context("Get video duration")?.parse::<f32>()?;
-        let duration = Duration::from_secs_f32(duration);
+        let probe = ffmpeg_wrapper::probe(p)?;
+        let video = probe.video().context("No video stream")?;
 
         Ok(Self {
-            height,
-            width,
-            duration,
+            height: video.height.context("Get video height")?,
+            width: video.width.context("Get video width")?,
+            duration: probe.duration().context("Get video duration")?,
+            frame_rate: video.frame_rate(),
+            codec: Codec::from_name(video.codec_name.as_deref().unwrap_or_default()),
         })
     }
 
     fn mp4(p: &Path) -> Result<Self> {
         let file = fs::File::open(p)?;
         let mp4 = mp4::read_mp4(file)?;
-        let (height, width) = mp4
+        let track = mp4
             .tracks()
             .values()
-            .map(|v| (v.height(), v.width()))
-            .max()
+            .filter(|v| matches!(v.track_type(), Ok(mp4::TrackType::Video)))
+            .max_by_key(|v| (v.height(), v.width()))
             .context("Cannot get the height")?;
         let duration = mp4.duration();
 
+        // Leave the codecs the mp4 crate does not know, e.g. AV1, to ffprobe
+        let codec = match track.media_type()? {
+            mp4::MediaType::H264 => Codec::H264,
+            mp4::MediaType::H265 => Codec::Hevc,
+            mp4::MediaType::VP9 => Codec::Vp9,
+            _ => bail!("Not a video track"),
+        };
+
         Ok(Self {
-            height: height as u32,
-            width: width as u32,
+            height: track.height() as u32,
+            width: track.width() as u32,
             duration,
+            frame_rate: Some(track.frame_rate()).filter(|v| *v > 0.0),
+            codec,
         })
     }
 
@@ -407,12 +1204,17 @@ impl VideoMetadata {
         let video = mkv.video_tracks().next().context("No video track")?;
 
         if let matroska::Settings::Video(ref v) = video.settings {
-            let height = v.display_height.unwrap_or(v.pixel_height);
-            let width = v.display_width.unwrap_or(v.pixel_width);
+            // Stored width like ffprobe gives, the bits per pixel are spent on the stored frames.
+            // The display size of anamorphic videos is only taken into account when scaling
             return Ok(Self {
-                height: height as u32,
-                width: width as u32,
+                height: v.pixel_height as u32,
+                width: v.pixel_width as u32,
                 duration,
+                frame_rate: video
+                    .default_duration
+                    .filter(|v| !v.is_zero())
+                    .map(|v| 1.0 / v.as_secs_f64()),
+                codec: Codec::from_matroska(&video.codec_id),
             });
         }
 
@@ -425,7 +1227,8 @@ struct SubFolder {
     path: PathBuf,
 }
 
-struct Videos {
+struct Videos<'a> {
+    index: &'a mut Index,
     sub: LinkedList<SubFolder>,
     ignores: Vec<PathBuf>,
     max_depth: u16,
@@ -433,14 +1236,20 @@ struct Videos {
     iter: fs::ReadDir,
 }
 
-impl Videos {
-    fn new(path: &Path, ignores: &[String], max_depth: u16) -> std::io::Result<Self> {
+impl<'a> Videos<'a> {
+    fn new(
+        path: &Path,
+        ignores: &[String],
+        max_depth: u16,
+        index: &'a mut Index,
+    ) -> std::io::Result<Self> {
         let ignores = ignores
-            .into_iter()
+            .iter()
             .filter_map(|v| PathBuf::from(v).canonicalize().ok())
             .collect::<Vec<_>>();
 
         fs::read_dir(path).map(|iter| Self {
+            index,
             max_depth,
             current_depth: 0,
             sub: Default::default(),
@@ -450,7 +1259,7 @@ impl Videos {
     }
 }
 
-impl Iterator for Videos {
+impl Iterator for Videos<'_> {
     type Item = Video;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -477,7 +1286,7 @@ impl Iterator for Videos {
                         continue;
                     }
 
-                    match Video::from_path(&path) {
+                    match Video::from_path(&path, self.index) {
                         Ok(video) => return Some(video),
                         Err(why) => log::debug!("{:?} isn't a video\n{:#?}", path, why),
                     }
@@ -495,3 +1304,21 @@ impl Iterator for Videos {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn on_finish() {
+        let args = |v: &[&str]| Args::try_parse_from(["to_720p"].iter().chain(v)).unwrap();
+
+        assert_eq!(args(&[]).on_finish(false), None);
+        assert_eq!(args(&["-s"]).on_finish(false), Some(SHUTDOWN));
+        assert_eq!(args(&["--shutdown"]).on_finish(true), None);
+
+        // A hook chosen by the user is told about the cancel through TO720P_STATUS instead
+        let args = args(&["--on-finish", "notify-send done"]);
+        assert_eq!(args.on_finish(true), Some("notify-send done"));
+    }
+}
@@ -0,0 +1,181 @@
+//! ASF, the container of WMV, from its File Properties and Stream Properties objects
+
+use super::{fourcc_codec, read_part, Bytes};
+use crate::VideoMetadata;
+use anyhow::{ensure, Context as _, Result};
+use std::fs::File;
+use std::path::Path;
+use std::time::Duration;
+
+/// Header objects bigger than this are not worth reading, ffprobe can take them
+const HEADER_LIMIT: u64 = 16 * 1024 * 1024;
+
+const HEADER_OBJECT: Guid = guid(0x75B22630, 0x668E, 0x11CF, 0xA6D9, 0x00AA0062CE6C);
+const FILE_PROPERTIES: Guid = guid(0x8CABDCA1, 0xA947, 0x11CF, 0x8EE4, 0x00C00C205365);
+const STREAM_PROPERTIES: Guid = guid(0xB7DC0791, 0xA9B7, 0x11CF, 0x8EE6, 0x00C00C205365);
+const VIDEO_MEDIA: Guid = guid(0xBC19EFC0, 0x5B4D, 0x11CF, 0xA8FD, 0x00805F5C442B);
+
+type Guid = [u8; 16];
+
+/// A GUID as it is stored, the first three fields in little-endian
+const fn guid(a: u32, b: u16, c: u16, d: u16, e: u64) -> Guid {
+    let a = a.to_le_bytes();
+    let b = b.to_le_bytes();
+    let c = c.to_le_bytes();
+    let d = d.to_be_bytes();
+    let e = e.to_be_bytes();
+
+    [
+        a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], e[2], e[3], e[4], e[5], e[6],
+        e[7],
+    ]
+}
+
+pub fn read(path: &Path) -> Result<VideoMetadata> {
+    let mut file = File::open(path)?;
+    let head = read_part(&mut file, 0, 24)?;
+    let mut bytes = Bytes::new(&head);
+
+    ensure!(bytes.array()? == HEADER_OBJECT, "Not an ASF file");
+    let size = bytes.u64()?;
+    ensure!(size <= HEADER_LIMIT, "The ASF header is too big");
+
+    parse(&read_part(&mut file, 0, size)?)
+}
+
+struct FileProperties {
+    /// In 100ns units
+    play_duration: u64,
+    /// In milliseconds, included in the play duration
+    preroll: u64,
+}
+
+pub fn parse(data: &[u8]) -> Result<VideoMetadata> {
+    let mut bytes = Bytes::new(data);
+
+    ensure!(bytes.array()? == HEADER_OBJECT, "Not an ASF file");
+    let size = bytes.u64()? as usize;
+    let count = bytes.u32()?;
+    // reserved
+    bytes.skip(2)?;
+
+    let mut bytes = Bytes::new(data.get(30..size).context("Truncated ASF header")?);
+    let mut properties = None;
+    let mut video = None;
+
+    for _ in 0..count {
+        let id: Guid = bytes.array()?;
+        let size = bytes.u64()? as usize;
+        let object = bytes.take(size.checked_sub(24).context("Invalid object size")?)?;
+
+        match id {
+            FILE_PROPERTIES => properties = Some(file_properties(object)?),
+            STREAM_PROPERTIES if video.is_none() => video = video_stream(object)?,
+            _ => {}
+        }
+    }
+
+    let properties = properties.context("No file properties")?;
+    let (width, height, compression) = video.context("No video stream")?;
+
+    // In 100ns units, which a corrupt header can overflow
+    let play_duration = properties
+        .play_duration
+        .checked_mul(100)
+        .context("Invalid play duration")?;
+
+    let duration = Duration::from_nanos(play_duration)
+        .saturating_sub(Duration::from_millis(properties.preroll));
+
+    ensure!(
+        !duration.is_zero(),
+        "No duration, the file may be a live stream"
+    );
+    ensure!(width > 0 && height > 0, "No resolution");
+
+    Ok(VideoMetadata {
+        width,
+        height,
+        duration,
+        // Only in the extended stream properties, in the average time per frame
+        frame_rate: None,
+        codec: fourcc_codec(&compression),
+    })
+}
+
+fn file_properties(data: &[u8]) -> Result<FileProperties> {
+    let mut bytes = Bytes::new(data);
+    // file id, file size, creation date, data packets count
+    bytes.skip(16 + 8 + 8 + 8)?;
+    let play_duration = bytes.u64()?;
+    // send duration
+    bytes.skip(8)?;
+
+    Ok(FileProperties {
+        play_duration,
+        preroll: bytes.u64()?,
+    })
+}
+
+/// Width, height and FourCC of a video stream
+fn video_stream(data: &[u8]) -> Result<Option<(u32, u32, [u8; 4])>> {
+    let mut bytes = Bytes::new(data);
+
+    if bytes.array::<16>()? != VIDEO_MEDIA {
+        return Ok(None);
+    }
+
+    // error correction type, time offset, type-specific data length, error correction data
+    // length, flags, reserved
+    bytes.skip(16 + 8 + 4 + 4 + 2 + 4)?;
+
+    let width = bytes.u32()?;
+    let height = bytes.u32()?;
+    // reserved, format data size, then the BITMAPINFOHEADER up to the compression
+    bytes.skip(1 + 2 + 4 + 4 + 4 + 2 + 2)?;
+
+    Ok(Some((width, height, bytes.array()?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::native::fixture;
+    use crate::oversize::Codec;
+
+    #[test]
+    fn wmv3() {
+        let metadata = parse(&fixture("wmv3.wmv")).unwrap();
+
+        assert_eq!((metadata.width, metadata.height), (854, 480));
+        assert_eq!(metadata.codec, Codec::Other);
+        // 95s of play duration with a 3s preroll
+        assert_eq!(metadata.duration, Duration::from_secs(92));
+        assert_eq!(metadata.frame_rate, None);
+    }
+
+    #[test]
+    fn audio_only() {
+        let err = parse(&fixture("audio.wma")).unwrap_err();
+        assert_eq!(err.to_string(), "No video stream");
+    }
+
+    #[test]
+    fn truncated() {
+        let data = fixture("wmv3.wmv");
+        assert!(parse(&data[..data.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn oversized() {
+        let mut data = fixture("wmv3.wmv");
+        let at = data.windows(16).position(|v| v == FILE_PROPERTIES).unwrap();
+
+        // guid and size of the object, file id, file size, creation date, data packets count
+        let play_duration = at + 16 + 8 + 16 + 8 + 8 + 8;
+        data[play_duration..play_duration + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+
+        let err = parse(&data).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid play duration");
+    }
+}
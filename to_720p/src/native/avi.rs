@@ -0,0 +1,216 @@
+//! RIFF/AVI, the `avih` main header and the `strh`/`strf` headers of the first video stream
+
+use super::{fourcc_codec, read_head, Bytes};
+use crate::VideoMetadata;
+use anyhow::{bail, ensure, Context as _, Result};
+use std::path::Path;
+use std::time::Duration;
+
+/// The `hdrl` list comes first and is small, unless it is padded with a large `JUNK` chunk
+const HEAD_SIZE: u64 = 1024 * 1024;
+
+pub fn read(path: &Path) -> Result<VideoMetadata> {
+    parse(&read_head(path, HEAD_SIZE)?)
+}
+
+struct MainHeader {
+    micro_sec_per_frame: u32,
+    total_frames: u32,
+    width: u32,
+    height: u32,
+}
+
+struct StreamHeader {
+    handler: [u8; 4],
+    scale: u32,
+    rate: u32,
+    length: u32,
+}
+
+struct BitmapInfo {
+    width: i32,
+    height: i32,
+    compression: [u8; 4],
+}
+
+pub fn parse(data: &[u8]) -> Result<VideoMetadata> {
+    let mut bytes = Bytes::new(data);
+
+    ensure!(bytes.take(4)? == b"RIFF", "Not a RIFF file");
+    bytes.skip(4)?;
+    ensure!(bytes.take(4)? == b"AVI ", "Not an AVI file");
+
+    let (id, hdrl) = chunk(&mut bytes)?;
+    ensure!(&id == b"LIST" && hdrl.starts_with(b"hdrl"), "No hdrl list");
+
+    let mut main = None;
+    let mut video = None;
+    let mut hdrl = Bytes::new(&hdrl[4..]);
+
+    while hdrl.remaining() >= 8 {
+        let (id, data) = chunk(&mut hdrl)?;
+
+        match &id {
+            b"avih" => main = Some(main_header(data)?),
+            b"LIST" if data.starts_with(b"strl") && video.is_none() => {
+                video = video_stream(&data[4..])?;
+            }
+            _ => {}
+        }
+    }
+
+    let main = main.context("No avih header")?;
+    let (stream, format) = video.context("No video stream")?;
+
+    let width = format
+        .as_ref()
+        .map_or(main.width, |v| v.width.unsigned_abs());
+    let height = format
+        .as_ref()
+        .map_or(main.height, |v| v.height.unsigned_abs());
+
+    let (duration, frame_rate) = match stream.scale > 0 && stream.rate > 0 {
+        true => {
+            let frame_rate = stream.rate as f64 / stream.scale as f64;
+            (stream.length as f64 / frame_rate, Some(frame_rate))
+        }
+        false if main.micro_sec_per_frame > 0 => {
+            let frame_time = main.micro_sec_per_frame as f64 / 1e6;
+            (
+                main.total_frames as f64 * frame_time,
+                Some(1.0 / frame_time),
+            )
+        }
+        false => bail!("No frame rate"),
+    };
+
+    ensure!(width > 0 && height > 0, "No resolution");
+    ensure!(duration > 0.0, "No duration");
+
+    let codec = match format {
+        Some(ref v) if v.compression != [0; 4] => fourcc_codec(&v.compression),
+        _ => fourcc_codec(&stream.handler),
+    };
+
+    Ok(VideoMetadata {
+        width,
+        height,
+        duration: Duration::from_secs_f64(duration),
+        frame_rate,
+        codec,
+    })
+}
+
+/// The next chunk, without the padding byte of an odd size
+fn chunk<'a>(bytes: &mut Bytes<'a>) -> Result<([u8; 4], &'a [u8])> {
+    let id = bytes.array()?;
+    let size = bytes.u32()? as usize;
+    let data = bytes.take(size)?;
+
+    if size % 2 == 1 && bytes.remaining() > 0 {
+        bytes.skip(1)?;
+    }
+
+    Ok((id, data))
+}
+
+fn main_header(data: &[u8]) -> Result<MainHeader> {
+    let mut bytes = Bytes::new(data);
+    let micro_sec_per_frame = bytes.u32()?;
+    // max bytes per second, padding granularity, flags
+    bytes.skip(12)?;
+    let total_frames = bytes.u32()?;
+    // initial frames, streams, suggested buffer size
+    bytes.skip(12)?;
+
+    Ok(MainHeader {
+        micro_sec_per_frame,
+        total_frames,
+        width: bytes.u32()?,
+        height: bytes.u32()?,
+    })
+}
+
+/// The headers of a `strl` list if it holds a video stream
+fn video_stream(data: &[u8]) -> Result<Option<(StreamHeader, Option<BitmapInfo>)>> {
+    let mut bytes = Bytes::new(data);
+    let mut header = None;
+    let mut format = None;
+
+    while bytes.remaining() >= 8 {
+        let (id, data) = chunk(&mut bytes)?;
+        let mut data = Bytes::new(data);
+
+        match &id {
+            b"strh" => {
+                if data.take(4)? != b"vids" {
+                    return Ok(None);
+                }
+
+                let handler = data.array()?;
+                // flags, priority, language, initial frames
+                data.skip(12)?;
+
+                header = Some(StreamHeader {
+                    handler,
+                    scale: data.u32()?,
+                    rate: data.u32()?,
+                    length: {
+                        data.skip(4)?;
+                        data.u32()?
+                    },
+                });
+            }
+            b"strf" => {
+                // size of the header
+                data.skip(4)?;
+                let width = data.i32()?;
+                let height = data.i32()?;
+                // planes, bit count
+                data.skip(4)?;
+
+                format = Some(BitmapInfo {
+                    width,
+                    height,
+                    compression: data.array()?,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    Ok(header.map(|v| (v, format)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::native::fixture;
+    use crate::oversize::Codec;
+
+    #[test]
+    fn xvid() {
+        let metadata = parse(&fixture("xvid.avi")).unwrap();
+
+        assert_eq!((metadata.width, metadata.height), (640, 480));
+        assert_eq!(metadata.codec, Codec::Other);
+        assert_eq!(metadata.duration.as_secs(), 120);
+        assert!((metadata.frame_rate.unwrap() - 23.976).abs() < 0.001);
+    }
+
+    #[test]
+    fn h264() {
+        let metadata = parse(&fixture("h264.avi")).unwrap();
+
+        assert_eq!((metadata.width, metadata.height), (1280, 720));
+        assert_eq!(metadata.codec, Codec::H264);
+        assert_eq!(metadata.duration.as_secs(), 60);
+        assert_eq!(metadata.frame_rate, Some(25.0));
+    }
+
+    #[test]
+    fn not_avi() {
+        assert!(parse(&fixture("xvid.avi")[..32]).is_err());
+        assert!(parse(b"RIFF\0\0\0\0WAVEfmt ").is_err());
+    }
+}
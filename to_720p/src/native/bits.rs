@@ -0,0 +1,88 @@
+//! Bit reader for the parameter sets inside the video stream
+
+use anyhow::{bail, Result};
+
+/// Big-endian bit reader over the payload of a NAL unit or an MPEG-2 header
+pub struct Bits {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl Bits {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    /// The payload of a NAL unit without its emulation prevention bytes
+    pub fn from_nal(nal: &[u8]) -> Self {
+        let mut data = Vec::with_capacity(nal.len());
+        let mut zeros = 0;
+
+        for &byte in nal {
+            if zeros >= 2 && byte == 3 {
+                zeros = 0;
+                continue;
+            }
+
+            zeros = if byte == 0 { zeros + 1 } else { 0 };
+            data.push(byte);
+        }
+
+        Self::new(data)
+    }
+
+    pub fn bit(&mut self) -> Result<bool> {
+        let Some(byte) = self.data.get(self.pos / 8) else {
+            bail!("Unexpected end of the parameter set");
+        };
+
+        let bit = byte >> (7 - self.pos % 8) & 1;
+        self.pos += 1;
+        Ok(bit == 1)
+    }
+
+    /// Up to 32 bits as an unsigned number
+    pub fn bits(&mut self, count: u32) -> Result<u32> {
+        let mut value = 0;
+
+        for _ in 0..count {
+            value = value << 1 | self.bit()? as u32;
+        }
+
+        Ok(value)
+    }
+
+    pub fn skip(&mut self, count: usize) -> Result<()> {
+        if self.pos + count > self.data.len() * 8 {
+            bail!("Unexpected end of the parameter set");
+        }
+
+        self.pos += count;
+        Ok(())
+    }
+
+    /// Unsigned Exp-Golomb code
+    pub fn ue(&mut self) -> Result<u32> {
+        let mut zeros = 0;
+
+        while !self.bit()? {
+            zeros += 1;
+
+            if zeros > 31 {
+                bail!("Invalid Exp-Golomb code");
+            }
+        }
+
+        Ok((1 << zeros) - 1 + self.bits(zeros)?)
+    }
+
+    /// Signed Exp-Golomb code
+    pub fn se(&mut self) -> Result<i32> {
+        let value = self.ue()? as i64;
+
+        Ok(match value % 2 {
+            0 => -(value / 2),
+            _ => (value + 1) / 2,
+        } as i32)
+    }
+}
@@ -0,0 +1,96 @@
+//! Header readers for the containers no crate reads for us, so scanning a folder does not start
+//! an ffprobe for every AVI, MPEG-TS and WMV file
+
+pub mod asf;
+pub mod avi;
+mod bits;
+pub mod ts;
+
+use crate::oversize::Codec;
+use anyhow::{bail, Result};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Little-endian reader over a byte slice
+struct Bytes<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Bytes<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.remaining() < len {
+            bail!("Unexpected end of the header at byte {}", self.pos);
+        }
+
+        let data = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(data)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<()> {
+        self.take(len).map(|_| ())
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        self.array().map(u32::from_le_bytes)
+    }
+
+    fn i32(&mut self) -> Result<i32> {
+        self.array().map(i32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        self.array().map(u64::from_le_bytes)
+    }
+}
+
+/// Up to `len` bytes from `offset`, or from the end of the file when `offset` is negative
+fn read_part(file: &mut File, offset: i64, len: u64) -> Result<Vec<u8>> {
+    match offset {
+        0.. => file.seek(SeekFrom::Start(offset as u64))?,
+        _ => file.seek(SeekFrom::End(offset))?,
+    };
+
+    let mut data = Vec::new();
+    file.take(len).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// The first `len` bytes of the file
+fn read_head(path: &Path, len: u64) -> Result<Vec<u8>> {
+    read_part(&mut File::open(path)?, 0, len)
+}
+
+/// Codec of the FourCC of an AVI or ASF video stream
+fn fourcc_codec(fourcc: &[u8]) -> Codec {
+    match fourcc.to_ascii_uppercase().as_slice() {
+        b"H264" | b"X264" | b"AVC1" | b"DAVC" => Codec::H264,
+        b"HEVC" | b"H265" | b"HVC1" | b"HEV1" => Codec::Hevc,
+        b"VP90" => Codec::Vp9,
+        b"AV01" => Codec::Av1,
+        _ => Codec::Other,
+    }
+}
+
+#[cfg(test)]
+fn fixture(name: &str) -> Vec<u8> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name);
+
+    std::fs::read(&path).unwrap_or_else(|why| panic!("Cannot read {:?}: {why}", path))
+}
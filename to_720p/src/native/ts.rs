@@ -0,0 +1,628 @@
+//! MPEG transport stream, from the PAT and PMT, the sequence header of the video stream and the
+//! first and last PCR
+
+use super::bits::Bits;
+use super::read_part;
+use crate::oversize::Codec;
+use crate::VideoMetadata;
+use anyhow::{bail, ensure, Context as _, Result};
+use std::fs::File;
+use std::path::Path;
+use std::time::Duration;
+
+const PACKET_SIZE: usize = 188;
+const SYNC_BYTE: u8 = 0x47;
+
+/// Enough for the tables and the first keyframe of the video
+const HEAD_SIZE: u64 = 2 * 1024 * 1024;
+/// Enough for the last PCR
+const TAIL_SIZE: u64 = 256 * 1024;
+
+/// The PCR counts in 90kHz ticks and wraps around after 33 bits
+const PCR_FREQUENCY: f64 = 90_000.0;
+const PCR_WRAP: u64 = 1 << 33;
+
+pub fn read(path: &Path) -> Result<VideoMetadata> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let head = read_part(&mut file, 0, HEAD_SIZE)?;
+
+    let tail = match len > HEAD_SIZE {
+        true => read_part(
+            &mut file,
+            -(TAIL_SIZE.min(len - HEAD_SIZE) as i64),
+            TAIL_SIZE,
+        )?,
+        false => Vec::new(),
+    };
+
+    parse(&head, &tail)
+}
+
+/// Reads the stream from the start of the file and its end, the end is empty when the start
+/// already holds the whole file
+pub fn parse(head: &[u8], tail: &[u8]) -> Result<VideoMetadata> {
+    let head = packets(head).context("Not an MPEG transport stream")?;
+
+    let pmt_pid = head
+        .iter()
+        .filter(|v| v.pid == 0 && v.start)
+        .find_map(|v| pat(v.payload).ok().flatten())
+        .context("No PAT")?;
+
+    let (pcr_pid, video_pid, stream_type) = head
+        .iter()
+        .filter(|v| v.pid == pmt_pid && v.start)
+        .find_map(|v| pmt(v.payload).ok())
+        .context("No PMT")?
+        .context("No video stream")?;
+
+    let codec = match stream_type {
+        0x1B => Codec::H264,
+        0x24 => Codec::Hevc,
+        _ => Codec::Other,
+    };
+
+    let stream = video_stream(&head, video_pid);
+    let (width, height, frame_rate) = match stream_type {
+        0x1B => nal_units(&stream)
+            .filter(|v| v.first().map(|v| v & 0x1F) == Some(7))
+            .find_map(|v| h264_sps(&v[1..]).ok()),
+        0x24 => nal_units(&stream)
+            .filter(|v| v.first().map(|v| v >> 1 & 0x3F) == Some(33))
+            .find_map(|v| hevc_sps(v.get(2..)?).ok()),
+        0x01 | 0x02 => mpeg2_sequence_header(&stream),
+        _ => bail!("Unsupported video stream type {stream_type:#x}"),
+    }
+    .context("No sequence header in the video stream")?;
+
+    let first = head
+        .iter()
+        .filter(|v| v.pid == pcr_pid)
+        .find_map(|v| v.pcr)
+        .context("No PCR")?;
+
+    let tail = match tail.is_empty() {
+        true => None,
+        false => Some(packets(tail).context("Invalid end of the stream")?),
+    };
+
+    let last = tail
+        .as_ref()
+        .unwrap_or(&head)
+        .iter()
+        .rev()
+        .filter(|v| v.pid == pcr_pid)
+        .find_map(|v| v.pcr)
+        .context("No PCR at the end")?;
+
+    let ticks = (last + PCR_WRAP - first) % PCR_WRAP;
+    ensure!(ticks > 0, "No duration");
+
+    Ok(VideoMetadata {
+        width,
+        height,
+        duration: Duration::from_secs_f64(ticks as f64 / PCR_FREQUENCY),
+        frame_rate,
+        codec,
+    })
+}
+
+struct Packet<'a> {
+    pid: u16,
+    /// The payload starts a PES packet or a table section
+    start: bool,
+    /// In 90kHz ticks, without the 27MHz extension
+    pcr: Option<u64>,
+    payload: &'a [u8],
+}
+
+/// The packets of the data, starting at the first run of sync bytes
+fn packets(data: &[u8]) -> Result<Vec<Packet<'_>>> {
+    let synced = |at: usize| {
+        (0..3)
+            .map(|v| at + v * PACKET_SIZE)
+            .take_while(|&v| v < data.len())
+            .all(|v| data[v] == SYNC_BYTE)
+    };
+
+    let offset = (0..PACKET_SIZE.min(data.len()))
+        .find(|&v| synced(v))
+        .context("No sync byte")?;
+
+    let packets = data[offset..]
+        .chunks_exact(PACKET_SIZE)
+        .take_while(|v| v[0] == SYNC_BYTE)
+        .filter_map(|v| packet(v).ok())
+        .collect::<Vec<_>>();
+
+    ensure!(!packets.is_empty(), "No packet");
+    Ok(packets)
+}
+
+fn packet(data: &[u8]) -> Result<Packet<'_>> {
+    let pid = u16::from_be_bytes([data[1] & 0x1F, data[2]]);
+    let start = data[1] & 0x40 != 0;
+    let control = data[3] >> 4 & 0x3;
+
+    let mut offset = 4;
+    let mut pcr = None;
+
+    if control & 0x2 != 0 {
+        let len = data[4] as usize;
+        let field = data.get(5..5 + len).context("Invalid adaptation field")?;
+
+        if len >= 7 && field[0] & 0x10 != 0 {
+            let base = field[1..6].iter().fold(0u64, |acc, &v| acc << 8 | v as u64);
+            pcr = Some(base >> 7);
+        }
+
+        offset += 1 + len;
+    }
+
+    let payload = match control & 0x1 {
+        0 => &[][..],
+        _ => data.get(offset..).unwrap_or_default(),
+    };
+
+    Ok(Packet {
+        pid,
+        start,
+        pcr,
+        payload,
+    })
+}
+
+/// The section of a table, after the pointer field and up to the CRC
+fn section(payload: &[u8], table_id: u8) -> Result<&[u8]> {
+    let pointer = *payload.first().context("Empty section")? as usize;
+    let data = payload
+        .get(1 + pointer..)
+        .context("Invalid pointer field")?;
+
+    ensure!(data.len() >= 3 && data[0] == table_id, "Unexpected table");
+
+    let len = u16::from_be_bytes([data[1] & 0x0F, data[2]]) as usize;
+    let section = data.get(3..3 + len).context("Section across packets")?;
+
+    // the table id extension, version, section numbers, then the CRC at the end
+    section
+        .get(5..len.saturating_sub(4))
+        .context("Section too short")
+}
+
+/// PID of the PMT of the first program
+fn pat(payload: &[u8]) -> Result<Option<u16>> {
+    let section = section(payload, 0x00)?;
+
+    Ok(section
+        .chunks_exact(4)
+        .filter(|v| u16::from_be_bytes([v[0], v[1]]) != 0)
+        .map(|v| u16::from_be_bytes([v[2] & 0x1F, v[3]]))
+        .next())
+}
+
+/// PCR PID, then the PID and stream type of the first video stream
+fn pmt(payload: &[u8]) -> Result<Option<(u16, u16, u8)>> {
+    let section = section(payload, 0x02)?;
+    ensure!(section.len() >= 4, "PMT too short");
+
+    let pcr_pid = u16::from_be_bytes([section[0] & 0x1F, section[1]]);
+    let info_len = u16::from_be_bytes([section[2] & 0x0F, section[3]]) as usize;
+    let mut streams = section.get(4 + info_len..).context("Invalid PMT")?;
+
+    while streams.len() >= 5 {
+        let stream_type = streams[0];
+        let pid = u16::from_be_bytes([streams[1] & 0x1F, streams[2]]);
+        let info_len = u16::from_be_bytes([streams[3] & 0x0F, streams[4]]) as usize;
+
+        if matches!(stream_type, 0x01 | 0x02 | 0x10 | 0x1B | 0x24 | 0xEA) {
+            return Ok(Some((pcr_pid, pid, stream_type)));
+        }
+
+        streams = streams.get(5 + info_len..).unwrap_or_default();
+    }
+
+    Ok(None)
+}
+
+/// The elementary stream of `pid`, the payloads of its PES packets put together
+fn video_stream(packets: &[Packet], pid: u16) -> Vec<u8> {
+    let mut stream = Vec::new();
+    let mut started = false;
+
+    for packet in packets.iter().filter(|v| v.pid == pid) {
+        let mut payload = packet.payload;
+
+        if packet.start {
+            started = true;
+
+            // start code prefix, stream id and length, flags, then the optional fields
+            if payload.len() >= 9 && payload.starts_with(&[0, 0, 1]) {
+                let header_len = 9 + payload[8] as usize;
+                payload = payload.get(header_len..).unwrap_or_default();
+            }
+        }
+
+        if started {
+            stream.extend_from_slice(payload);
+        }
+    }
+
+    stream
+}
+
+/// The units between the `00 00 01` start codes
+fn nal_units(stream: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let starts = stream
+        .windows(3)
+        .enumerate()
+        .filter(|(_, v)| *v == [0, 0, 1])
+        .map(|(i, _)| i + 3)
+        .collect::<Vec<_>>();
+
+    let ends = starts
+        .iter()
+        .skip(1)
+        .map(|&v| v - 3)
+        .chain([stream.len()])
+        .collect::<Vec<_>>();
+
+    starts.into_iter().zip(ends).map(move |(start, end)| {
+        stream[start..end]
+            .strip_suffix(&[0])
+            .unwrap_or(&stream[start..end])
+    })
+}
+
+/// Width, height and frame rate from an H.264 sequence parameter set, after its NAL header
+fn h264_sps(data: &[u8]) -> Result<(u32, u32, Option<f64>)> {
+    let mut bits = Bits::from_nal(data);
+
+    let profile = bits.bits(8)?;
+    // constraint flags, level
+    bits.skip(16)?;
+    bits.ue()?;
+
+    let mut chroma_format = 1;
+
+    if matches!(
+        profile,
+        100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+    ) {
+        chroma_format = bits.ue()?;
+
+        if chroma_format == 3 {
+            bits.skip(1)?;
+        }
+
+        // bit depths, transform bypass
+        bits.ue()?;
+        bits.ue()?;
+        bits.skip(1)?;
+
+        if bits.bit()? {
+            let lists = if chroma_format == 3 { 12 } else { 8 };
+
+            for i in 0..lists {
+                if bits.bit()? {
+                    skip_scaling_list(&mut bits, if i < 6 { 16 } else { 64 })?;
+                }
+            }
+        }
+    }
+
+    // log2 of the max frame number
+    bits.ue()?;
+
+    match bits.ue()? {
+        0 => {
+            bits.ue()?;
+        }
+        1 => {
+            bits.skip(1)?;
+            bits.se()?;
+            bits.se()?;
+
+            for _ in 0..bits.ue()? {
+                bits.se()?;
+            }
+        }
+        _ => {}
+    }
+
+    // reference frames, gaps allowed
+    bits.ue()?;
+    bits.skip(1)?;
+
+    let width_mbs = bits.ue()? + 1;
+    let height_map_units = bits.ue()? + 1;
+    let frame_mbs_only = bits.bit()?;
+
+    if !frame_mbs_only {
+        bits.skip(1)?;
+    }
+
+    // direct 8x8 inference
+    bits.skip(1)?;
+
+    let (sub_width, sub_height) = match chroma_format {
+        1 => (2, 2),
+        2 => (2, 1),
+        _ => (1, 1),
+    };
+
+    let field_factor = if frame_mbs_only { 1 } else { 2 };
+    let crop_x = if chroma_format == 0 { 1 } else { sub_width };
+    let crop_y = field_factor * if chroma_format == 0 { 1 } else { sub_height };
+
+    // Sizes from a corrupt header can overflow
+    let mut width = width_mbs.checked_mul(16).context("Invalid width")?;
+    let mut height = height_map_units
+        .checked_mul(field_factor * 16)
+        .context("Invalid height")?;
+
+    if bits.bit()? {
+        let (left, right, top, bottom) = (bits.ue()?, bits.ue()?, bits.ue()?, bits.ue()?);
+        width = width.saturating_sub(cropped(crop_x, left, right)?);
+        height = height.saturating_sub(cropped(crop_y, top, bottom)?);
+    }
+
+    let frame_rate = match bits.bit()? {
+        true => h264_vui_frame_rate(&mut bits).ok().flatten(),
+        false => None,
+    };
+
+    Ok((width, height, frame_rate))
+}
+
+/// Pixels cropped off two opposite sides, given in units of `unit` pixels
+fn cropped(unit: u32, a: u32, b: u32) -> Result<u32> {
+    a.checked_add(b)
+        .and_then(|v| v.checked_mul(unit))
+        .context("Invalid cropping")
+}
+
+fn skip_scaling_list(bits: &mut Bits, size: usize) -> Result<()> {
+    let mut last = 8;
+    let mut next = 8;
+
+    for _ in 0..size {
+        if next != 0 {
+            next = (last + i64::from(bits.se()?)).rem_euclid(256);
+        }
+
+        if next != 0 {
+            last = next;
+        }
+    }
+
+    Ok(())
+}
+
+fn h264_vui_frame_rate(bits: &mut Bits) -> Result<Option<f64>> {
+    // aspect ratio
+    if bits.bit()? && bits.bits(8)? == 255 {
+        bits.skip(32)?;
+    }
+
+    // overscan
+    if bits.bit()? {
+        bits.skip(1)?;
+    }
+
+    // video signal type, colour description
+    if bits.bit()? {
+        bits.skip(4)?;
+
+        if bits.bit()? {
+            bits.skip(24)?;
+        }
+    }
+
+    // chroma location
+    if bits.bit()? {
+        bits.ue()?;
+        bits.ue()?;
+    }
+
+    if !bits.bit()? {
+        return Ok(None);
+    }
+
+    let units_in_tick = bits.bits(32)?;
+    let time_scale = bits.bits(32)?;
+
+    Ok((units_in_tick > 0).then(|| time_scale as f64 / (2 * units_in_tick as u64) as f64))
+}
+
+/// Width and height from an HEVC sequence parameter set, after its NAL header
+fn hevc_sps(data: &[u8]) -> Result<(u32, u32, Option<f64>)> {
+    let mut bits = Bits::from_nal(data);
+
+    // video parameter set id
+    bits.skip(4)?;
+    let sub_layers = bits.bits(3)?;
+    bits.skip(1)?;
+
+    // general profile, tier and level
+    bits.skip(96)?;
+
+    let mut present = Vec::new();
+
+    for _ in 0..sub_layers {
+        present.push((bits.bit()?, bits.bit()?));
+    }
+
+    if sub_layers > 0 {
+        bits.skip(2 * (8 - sub_layers as usize))?;
+    }
+
+    for (profile, level) in present {
+        if profile {
+            bits.skip(88)?;
+        }
+
+        if level {
+            bits.skip(8)?;
+        }
+    }
+
+    bits.ue()?;
+    let chroma_format = bits.ue()?;
+
+    if chroma_format == 3 {
+        bits.skip(1)?;
+    }
+
+    let mut width = bits.ue()?;
+    let mut height = bits.ue()?;
+
+    if bits.bit()? {
+        let (sub_width, sub_height) = match chroma_format {
+            1 => (2, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        };
+
+        let (left, right, top, bottom) = (bits.ue()?, bits.ue()?, bits.ue()?, bits.ue()?);
+        width = width.saturating_sub(cropped(sub_width, left, right)?);
+        height = height.saturating_sub(cropped(sub_height, top, bottom)?);
+    }
+
+    Ok((width, height, None))
+}
+
+/// Width, height and frame rate from the MPEG-1/2 sequence header
+fn mpeg2_sequence_header(stream: &[u8]) -> Option<(u32, u32, Option<f64>)> {
+    let at = stream.windows(4).position(|v| v == [0, 0, 1, 0xB3])? + 4;
+    let mut bits = Bits::new(stream.get(at..at + 4)?.to_vec());
+
+    let width = bits.bits(12).ok()?;
+    let height = bits.bits(12).ok()?;
+    bits.skip(4).ok()?;
+
+    let frame_rate = match bits.bits(4).ok()? {
+        1 => Some(24000.0 / 1001.0),
+        2 => Some(24.0),
+        3 => Some(25.0),
+        4 => Some(30000.0 / 1001.0),
+        5 => Some(30.0),
+        6 => Some(50.0),
+        7 => Some(60000.0 / 1001.0),
+        8 => Some(60.0),
+        _ => None,
+    };
+
+    Some((width, height, frame_rate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::native::fixture;
+
+    #[test]
+    fn h264() {
+        let data = fixture("h264.ts");
+        let metadata = parse(&data, &[]).unwrap();
+
+        assert_eq!((metadata.width, metadata.height), (1920, 1080));
+        assert_eq!(metadata.codec, Codec::H264);
+        assert_eq!(metadata.duration, Duration::from_secs(90));
+        assert!((metadata.frame_rate.unwrap() - 29.97).abs() < 0.01);
+    }
+
+    #[test]
+    fn h264_split() {
+        let data = fixture("h264.ts");
+        let (head, tail) = data.split_at(PACKET_SIZE * 6 + 100);
+        let metadata = parse(head, &tail[tail.len() - PACKET_SIZE * 2 - 50..]).unwrap();
+
+        assert_eq!(metadata.duration, Duration::from_secs(90));
+    }
+
+    #[test]
+    fn hevc() {
+        let metadata = parse(&fixture("hevc.ts"), &[]).unwrap();
+
+        assert_eq!((metadata.width, metadata.height), (1280, 720));
+        assert_eq!(metadata.codec, Codec::Hevc);
+        assert_eq!(metadata.duration, Duration::from_secs(10));
+    }
+
+    #[test]
+    fn mpeg2() {
+        let metadata = parse(&fixture("mpeg2.ts"), &[]).unwrap();
+
+        assert_eq!((metadata.width, metadata.height), (720, 576));
+        assert_eq!(metadata.codec, Codec::Other);
+        assert_eq!(metadata.frame_rate, Some(25.0));
+        assert_eq!(metadata.duration, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn not_ts() {
+        assert!(parse(&[0; 1024], &[]).is_err());
+    }
+
+    /// A baseline H.264 SPS with the given size in macroblocks and cropping
+    fn sps(width_mbs: u64, height_mbs: u64, crop: Option<[u64; 4]>) -> Vec<u8> {
+        let mut bits = String::new();
+        let ue = |bits: &mut String, v: u64| {
+            let code = format!("{:b}", v + 1);
+            bits.push_str(&"0".repeat(code.len() - 1));
+            bits.push_str(&code);
+        };
+
+        // profile 66, constraint flags, level 31
+        bits.push_str("010000100000000000011111");
+        // id, log2 of the max frame number, picture order count type 2, reference frames
+        for v in [0, 0, 2, 1] {
+            ue(&mut bits, v);
+        }
+        // gaps not allowed
+        bits.push('0');
+        ue(&mut bits, width_mbs - 1);
+        ue(&mut bits, height_mbs - 1);
+        // frame only, direct 8x8
+        bits.push_str("11");
+
+        match crop {
+            Some(crop) => {
+                bits.push('1');
+                crop.into_iter().for_each(|v| ue(&mut bits, v));
+            }
+            None => bits.push('0'),
+        }
+
+        // no VUI, stop bit
+        bits.push_str("01");
+
+        bits.push_str(&"0".repeat((8 - bits.len() % 8) % 8));
+        bits.as_bytes()
+            .chunks(8)
+            .map(|v| u8::from_str_radix(std::str::from_utf8(v).unwrap(), 2).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn sps_size() {
+        let (width, height, _) = h264_sps(&sps(120, 68, Some([0, 0, 0, 4]))).unwrap();
+        assert_eq!((width, height), (1920, 1080));
+    }
+
+    #[test]
+    fn oversized() {
+        let max = u32::MAX as u64 - 1;
+
+        let err = h264_sps(&sps(max + 1, 68, None)).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid width");
+
+        let err = h264_sps(&sps(120, 68, Some([max, 1, 0, 0]))).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid cropping");
+
+        let err = h264_sps(&sps(120, 68, Some([0, 0, max, 0]))).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid cropping");
+    }
+}
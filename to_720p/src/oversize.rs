@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Used when the container does not tell the frame rate
+pub const DEFAULT_FRAME_RATE: f64 = 30.0;
+
+/// Bits per pixel per frame above which a video is worth encoding again, tuned for 720p and
+/// above. The newer codecs need fewer bits for the same quality.
+const DEFAULT_LIMITS: &[(Codec, f64)] = &[
+    (Codec::H264, 0.10),
+    (Codec::Hevc, 0.065),
+    (Codec::Vp9, 0.07),
+    (Codec::Av1, 0.05),
+    (Codec::Other, 0.12),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    H264,
+    Hevc,
+    Vp9,
+    Av1,
+    /// MPEG-4 part 2, WMV and the like
+    Other,
+}
+
+impl Codec {
+    /// From an ffprobe codec name or a short name given by the user
+    pub fn from_name(name: &str) -> Self {
+        match name.to_ascii_lowercase().as_str() {
+            "h264" | "avc" | "avc1" => Self::H264,
+            "hevc" | "h265" | "hvc1" | "hev1" => Self::Hevc,
+            "vp9" => Self::Vp9,
+            "av1" | "av01" => Self::Av1,
+            _ => Self::Other,
+        }
+    }
+
+    /// From a Matroska codec id like `V_MPEG4/ISO/AVC`
+    pub fn from_matroska(codec_id: &str) -> Self {
+        match codec_id {
+            "V_MPEG4/ISO/AVC" => Self::H264,
+            "V_MPEGH/ISO/HEVC" => Self::Hevc,
+            "V_VP9" => Self::Vp9,
+            "V_AV1" => Self::Av1,
+            _ => Self::Other,
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::H264 => "h264",
+            Self::Hevc => "hevc",
+            Self::Vp9 => "vp9",
+            Self::Av1 => "av1",
+            Self::Other => "other",
+        };
+
+        f.pad(s)
+    }
+}
+
+/// A `--max-bpp` value like `hevc=0.07`
+pub fn parse_limit(s: &str) -> Result<(Codec, f64), String> {
+    let (codec, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Expected CODEC=BPP, got {s:?}"))?;
+
+    let codec = match codec.trim() {
+        "other" => Codec::Other,
+        name => match Codec::from_name(name) {
+            Codec::Other => return Err(format!("Unknown codec {name:?}")),
+            codec => codec,
+        },
+    };
+
+    match value.trim().parse::<f64>() {
+        Ok(v) if v > 0.0 => Ok((codec, v)),
+        _ => Err(format!("{value:?} is not a positive number")),
+    }
+}
+
+/// The bits per pixel per frame allowed for each codec
+#[derive(Debug, Clone)]
+pub struct Limits(Vec<(Codec, f64)>);
+
+impl Limits {
+    /// The defaults overridden by the given values
+    pub fn new(overrides: &[(Codec, f64)]) -> Self {
+        let mut limits = DEFAULT_LIMITS.to_vec();
+
+        for &(codec, value) in overrides {
+            if let Some(limit) = limits.iter_mut().find(|(v, _)| *v == codec) {
+                limit.1 = value;
+            }
+        }
+
+        Self(limits)
+    }
+
+    pub fn get(&self, codec: Codec) -> f64 {
+        self.0
+            .iter()
+            .find(|(v, _)| *v == codec)
+            .map(|(_, v)| *v)
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limit() {
+        assert_eq!(parse_limit("hevc=0.07"), Ok((Codec::Hevc, 0.07)));
+        assert_eq!(parse_limit("h265=0.07"), Ok((Codec::Hevc, 0.07)));
+        assert_eq!(parse_limit(" AVC = 0.1 "), Ok((Codec::H264, 0.1)));
+        assert_eq!(parse_limit("other=0.2"), Ok((Codec::Other, 0.2)));
+
+        assert!(parse_limit("hevc").is_err());
+        assert!(parse_limit("mpeg2=0.1").is_err());
+        assert!(parse_limit("av1=0").is_err());
+        assert!(parse_limit("av1=much").is_err());
+    }
+
+    #[test]
+    fn overrides() {
+        let limits = Limits::new(&[(Codec::Av1, 0.2), (Codec::Av1, 0.3)]);
+
+        assert_eq!(limits.get(Codec::Av1), 0.3);
+        assert_eq!(limits.get(Codec::H264), 0.10);
+    }
+}
@@ -0,0 +1,171 @@
+use ffmpeg_wrapper::Progress;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use macros::LogFormat;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+const FILE_TEMPLATE: &str = "{prefix:24!} [{bar:40.cyan/blue}] {percent:>3}% {msg} ETA {eta}";
+const BATCH_TEMPLATE: &str =
+    "{prefix:24!} [{bar:40.green/white}] {percent:>3}% {msg} elapsed {elapsed}, ETA {eta}";
+
+/// The progress bars of the files being encoded and of the whole batch.
+///
+/// The bars count milliseconds of the input that have been encoded, so their ETA follows the
+/// encoding speed and the durations still left in the queue. Nothing is drawn with
+/// `--log-format json` or when stderr is not a terminal.
+pub struct Bars {
+    multi: MultiProgress,
+    batch: ProgressBar,
+    workers: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: usize,
+    /// Input already encoded by the finished files of the batch
+    done: u64,
+    finished: usize,
+    count: usize,
+    /// Input encoded so far by each file in progress
+    running: HashMap<usize, u64>,
+}
+
+/// The bar of a single file, counted into the batch once dropped
+pub struct FileBar<'a> {
+    bars: &'a Bars,
+    bar: ProgressBar,
+    id: usize,
+    duration: Duration,
+    passes: usize,
+}
+
+impl Bars {
+    /// Bars for `count` files lasting `total` altogether, encoded by `workers` at once
+    pub fn new(total: Duration, count: usize, workers: usize) -> Self {
+        let target = match macros::global().log_format {
+            LogFormat::Json => ProgressDrawTarget::hidden(),
+            LogFormat::Text => ProgressDrawTarget::stderr(),
+        };
+
+        let multi = MultiProgress::with_draw_target(target);
+
+        let batch = multi.add(ProgressBar::new(millis(total)));
+        batch.set_style(style(BATCH_TEMPLATE));
+        batch.set_prefix("batch");
+        batch.set_message(format!("0/{count}"));
+
+        let state = State {
+            count,
+            ..Default::default()
+        };
+
+        Self {
+            multi,
+            batch,
+            workers,
+            state: Mutex::new(state),
+        }
+    }
+
+    /// Add the bar of a file that is about to be encoded
+    pub fn file(&self, name: &str, duration: Duration) -> FileBar<'_> {
+        let bar = self
+            .multi
+            .insert_before(&self.batch, ProgressBar::new(millis(duration)));
+        bar.set_style(style(FILE_TEMPLATE));
+        bar.set_prefix(name.to_owned());
+
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.running.insert(id, 0);
+
+        FileBar {
+            bars: self,
+            bar,
+            id,
+            duration,
+            passes: 1,
+        }
+    }
+
+    /// Whether ffmpeg may write to the terminal, only when it is the only one running
+    pub fn echo(&self) -> bool {
+        self.workers == 1
+    }
+
+    /// Run `f` with the bars hidden, for logging without tearing them apart
+    pub fn suspend<R>(&self, f: impl FnOnce() -> R) -> R {
+        self.multi.suspend(f)
+    }
+
+    pub fn finish(&self) {
+        self.batch.finish_and_clear();
+    }
+
+    fn update(&self, state: &State) {
+        let running = state.running.values().sum::<u64>();
+        self.batch.set_position(state.done + running);
+        self.batch
+            .set_message(format!("{}/{}", state.finished, state.count));
+    }
+}
+
+impl FileBar<'_> {
+    /// The file is encoded in `passes` runs of ffmpeg
+    pub fn set_passes(&mut self, passes: usize) {
+        self.passes = passes.max(1);
+        self.bar
+            .set_length(millis(self.duration) * self.passes as u64);
+    }
+
+    /// Update the bars with a report of the `pass`-th run of ffmpeg, counted from 0
+    pub fn update(&self, pass: usize, progress: &Progress) {
+        let out_time = progress.out_time.unwrap_or_default().min(self.duration);
+        let position = millis(self.duration) * pass as u64 + millis(out_time);
+
+        self.bar.set_position(position);
+
+        if let Some(speed) = progress.speed {
+            self.bar.set_message(format!("{speed:.2}x"));
+        }
+
+        let mut state = self.bars.state.lock().unwrap();
+        state.running.insert(self.id, position / self.passes as u64);
+        self.bars.update(&state);
+    }
+
+    pub fn suspend<R>(&self, f: impl FnOnce() -> R) -> R {
+        self.bars.suspend(f)
+    }
+
+    pub fn echo(&self) -> bool {
+        self.bars.echo()
+    }
+}
+
+/// Count the file as done, whether it succeeded or not
+impl Drop for FileBar<'_> {
+    fn drop(&mut self) {
+        self.bar.finish_and_clear();
+        self.bars.multi.remove(&self.bar);
+
+        let mut state = self.bars.state.lock().unwrap();
+        state.running.remove(&self.id);
+        state.done += millis(self.duration);
+        state.finished += 1;
+        self.bars.update(&state);
+    }
+}
+
+fn style(template: &str) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .expect("valid template")
+        .progress_chars("=> ")
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
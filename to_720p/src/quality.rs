@@ -0,0 +1,113 @@
+//! How close the output looks to the source, from the `ssim` and `psnr` filters of ffmpeg run
+//! over a few segments
+
+use anyhow::{Context as _, Result};
+use ffmpeg_wrapper::Ffmpeg;
+use macros::cancel::{self, Cancelled};
+use std::path::Path;
+use std::time::Duration;
+
+/// Where the compared segments start, as a share of the duration
+const SAMPLE_POSITIONS: &[f64] = &[0.2, 0.5, 0.8];
+
+/// Length of each compared segment
+const SAMPLE_LENGTH: Duration = Duration::from_secs(5);
+
+/// Puts both sides on the same clock and pixel format before they are compared
+const NORMALIZE: &str = "settb=AVTB,setpts=PTS-STARTPTS,format=yuv420p";
+
+/// Scores of the worst segment, banding in a single scene is enough to spoil a video
+#[derive(Debug, Clone, Copy)]
+pub struct Score {
+    /// 1 for identical pictures
+    pub ssim: f64,
+    /// In dB, infinite for identical pictures
+    pub psnr: f64,
+}
+
+/// Compare `output` with `source` once `reference` is applied to the source, the filter chain
+/// that makes a source frame look like an output one
+pub fn score(
+    source: &Path,
+    output: &Path,
+    duration: Duration,
+    reference: Option<&str>,
+) -> Result<Score> {
+    let reference = match reference {
+        Some(v) => format!("{v},{NORMALIZE}"),
+        None => NORMALIZE.to_owned(),
+    };
+
+    let graph = format!(
+        "[0:v]{NORMALIZE},split[out0][out1];[1:v]{reference},split[ref0][ref1];\
+         [out0][ref0]ssim;[out1][ref1]psnr"
+    );
+
+    let mut score: Option<Score> = None;
+
+    for &position in SAMPLE_POSITIONS {
+        if cancel::is_cancelled() {
+            return Err(Cancelled.into());
+        }
+
+        let start = duration.mul_f64(position);
+        let segment = compare(source, output, start, &graph).with_context(|| {
+            format!("Cannot compare the segment at {:.1}s", start.as_secs_f64())
+        })?;
+
+        if let Some(segment) = segment {
+            score = Some(match score {
+                Some(v) => Score {
+                    ssim: v.ssim.min(segment.ssim),
+                    psnr: v.psnr.min(segment.psnr),
+                },
+                None => segment,
+            });
+        }
+    }
+
+    score.context("No segment could be compared")
+}
+
+/// The scores of one segment, `None` when it has no frame
+fn compare(source: &Path, output: &Path, start: Duration, graph: &str) -> Result<Option<Score>> {
+    let seek = |ffmpeg: Ffmpeg| {
+        ffmpeg
+            .arg("-ss")
+            .arg(format!("{:.3}", start.as_secs_f64()))
+            .arg("-t")
+            .arg(format!("{:.3}", SAMPLE_LENGTH.as_secs_f64()))
+    };
+
+    let ffmpeg = Ffmpeg::new()
+        .echo(false)
+        .args(["-loglevel", "info", "-nostats"]);
+
+    let log = seek(seek(ffmpeg).input(output))
+        .input(source)
+        .args(["-lavfi", graph, "-f", "null"])
+        .output("-")
+        .cancel_when(cancel::is_cancelled)
+        .run_for_log()?;
+
+    let ssim = log
+        .lines()
+        .filter(|v| v.contains("Parsed_ssim"))
+        .find_map(|v| value(v, "All"));
+
+    let psnr = log
+        .lines()
+        .filter(|v| v.contains("Parsed_psnr"))
+        .find_map(|v| value(v, "average"));
+
+    Ok(match (ssim, psnr) {
+        (Some(ssim), Some(psnr)) => Some(Score { ssim, psnr }),
+        _ => None,
+    })
+}
+
+/// The number after `key:` in the summary line of a filter
+fn value(line: &str, key: &str) -> Option<f64> {
+    let (_, rest) = line.split_once(&format!(" {key}:"))?;
+    rest.split_whitespace().next()?.parse().ok()
+}
@@ -0,0 +1,164 @@
+use anyhow::{Context as _, Result};
+use chrono::{DateTime, Local};
+use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const QUEUE_FILE: &str = "queue.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pending,
+    /// Started but never finished, the machine went down in the middle of it
+    Running,
+    Done,
+    Failed,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Pending => "pending",
+            Self::Running => "running",
+            Self::Done => "done",
+            Self::Failed => "failed",
+        };
+
+        f.pad(s)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub path: PathBuf,
+    pub status: Status,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated: Option<DateTime<Local>>,
+}
+
+/// The work list of a batch, saved after every change so an interrupted batch can be resumed
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Queue {
+    #[serde(skip)]
+    path: PathBuf,
+    pub created: DateTime<Local>,
+    pub jobs: Vec<Job>,
+}
+
+impl Queue {
+    /// Where the queue is kept unless `--queue` says otherwise
+    pub fn default_path() -> PathBuf {
+        ProjectDirs::from("", "tmokenc", "tmoutils")
+            .map(|v| v.data_dir().join("to_720p"))
+            .unwrap_or_default()
+            .join(QUEUE_FILE)
+    }
+
+    pub fn new(path: impl Into<PathBuf>, videos: impl IntoIterator<Item = PathBuf>) -> Self {
+        let jobs = videos
+            .into_iter()
+            .map(|v| Job {
+                path: v.canonicalize().unwrap_or(v),
+                status: Status::Pending,
+                error: None,
+                updated: None,
+            })
+            .collect();
+
+        Self {
+            path: path.into(),
+            created: Local::now(),
+            jobs,
+        }
+    }
+
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Cannot read the queue {:?}", path))?;
+
+        let mut queue: Self = serde_json::from_str(&content)
+            .with_context(|| format!("Cannot parse the queue {:?}", path))?;
+
+        queue.path = path;
+        Ok(queue)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Write the queue out, through a temporary file so a crash never leaves half of it
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&tmp, &self.path)
+            .with_context(|| format!("Cannot save the queue {:?}", self.path))
+    }
+
+    /// The jobs that still have to be done, including the ones that got interrupted
+    pub fn remaining(&self) -> impl Iterator<Item = usize> + '_ {
+        self.jobs
+            .iter()
+            .enumerate()
+            .filter(|(_, job)| matches!(job.status, Status::Pending | Status::Running))
+            .map(|(i, _)| i)
+    }
+
+    /// Update the status of a job and save the queue right away
+    pub fn set(&mut self, index: usize, status: Status, error: Option<String>) -> Result<()> {
+        let job = &mut self.jobs[index];
+        job.status = status;
+        job.error = error;
+        job.updated = Some(Local::now());
+        self.save()
+    }
+
+    pub fn count(&self, status: Status) -> usize {
+        self.jobs.iter().filter(|v| v.status == status).count()
+    }
+
+    /// Print the state of every job
+    pub fn print_status(&self) {
+        println!("Queue {:?}", self.path);
+        println!("Created {}", self.created.format("%Y-%m-%d %H:%M:%S"));
+
+        for job in &self.jobs {
+            let updated = job
+                .updated
+                .map(|v| v.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_default();
+
+            println!("[{:<7}] {:<19} {}", job.status, updated, job.path.display());
+
+            if let Some(ref error) = job.error {
+                for line in error.lines() {
+                    println!("{:>30}{line}", "");
+                }
+            }
+        }
+
+        let statuses = [
+            Status::Pending,
+            Status::Running,
+            Status::Done,
+            Status::Failed,
+        ];
+
+        let counts = statuses
+            .iter()
+            .map(|&v| format!("{} {v}", self.count(v)))
+            .collect::<Vec<_>>();
+
+        println!("{} jobs: {}", self.jobs.len(), counts.join(", "));
+    }
+}
@@ -0,0 +1,84 @@
+use anyhow::{bail, ensure, Context as _, Result};
+use ffmpeg_wrapper::{Ffmpeg, Probe};
+use std::path::Path;
+use std::time::Duration;
+
+/// Difference in duration always allowed between the input and the output
+const DURATION_TOLERANCE: Duration = Duration::from_secs(2);
+
+/// Share of the duration allowed on top of [`DURATION_TOLERANCE`] for long videos
+const DURATION_TOLERANCE_RATIO: f64 = 0.005;
+
+/// Where the sample frames are decoded, as a share of the duration
+const SAMPLE_POSITIONS: &[f64] = &[0.1, 0.5, 0.9];
+
+/// The streams the output is supposed to have
+#[derive(Debug, Clone, Copy)]
+pub struct Expected {
+    pub videos: usize,
+    pub audios: usize,
+    pub subtitles: usize,
+}
+
+/// Check that `output` is a complete encode of `input` before the input is thrown away.
+///
+/// The durations must match, the output must have the expected streams, and a few frames spread
+/// over the output must decode without errors.
+pub fn verify(input: &Probe, output: &Path, expected: Expected) -> Result<()> {
+    let probed = ffmpeg_wrapper::probe(output).context("Cannot probe the output")?;
+
+    let duration = input.duration().context("Unknown duration of the input")?;
+    let output_duration = probed
+        .duration()
+        .context("Unknown duration of the output")?;
+    let tolerance = DURATION_TOLERANCE + duration.mul_f64(DURATION_TOLERANCE_RATIO);
+
+    ensure!(
+        duration.abs_diff(output_duration) <= tolerance,
+        "The output lasts {:.1}s instead of {:.1}s",
+        output_duration.as_secs_f64(),
+        duration.as_secs_f64()
+    );
+
+    let videos = probed.videos().count();
+    let audios = probed.audios().count();
+    let subtitles = probed.subtitles().count();
+
+    ensure!(
+        videos == expected.videos && audios == expected.audios && subtitles == expected.subtitles,
+        "The output has {videos} video, {audios} audio and {subtitles} subtitle streams instead \
+         of {}, {} and {}",
+        expected.videos,
+        expected.audios,
+        expected.subtitles
+    );
+
+    for &position in SAMPLE_POSITIONS {
+        let at = output_duration.mul_f64(position);
+        decode_frame(output, at)
+            .with_context(|| format!("Cannot decode a frame at {:.1}s", at.as_secs_f64()))?;
+    }
+
+    Ok(())
+}
+
+/// Decode a single frame at `at` and fail on any decoding error
+fn decode_frame(path: &Path, at: Duration) -> Result<()> {
+    let mut frames = 0;
+
+    Ffmpeg::new()
+        .echo(false)
+        .args(["-v", "error", "-xerror"])
+        .arg("-ss")
+        .arg(format!("{:.3}", at.as_secs_f64()))
+        .input(path)
+        .args(["-map", "0:v:0", "-frames:v", "1", "-f", "null"])
+        .output("-")
+        .run_with_progress(|progress| frames = progress.frame.unwrap_or(frames))?;
+
+    if frames == 0 {
+        bail!("No frame came out");
+    }
+
+    Ok(())
+}
@@ -1,4 +1,6 @@
 use clap::*;
+use macros::plan::Plan;
+use macros::watch;
 use std::fs;
 
 #[derive(Debug, Parser)]
@@ -8,6 +10,9 @@ pub struct Args {
     /// The base location to start with, default to the current one
     #[arg(default_value = ".")]
     location: String,
+
+    #[command(flatten)]
+    watch: watch::WatchArgs,
 }
 
 impl Args {
@@ -15,31 +20,29 @@ impl Args {
         let files = fs::read_dir(&self.location)?
             .filter_map(Result::ok)
             .map(|v| v.path())
-            .filter(|v| v.is_file())
+            .filter(|v| v.is_file() && watch::includes(v))
             .inspect(|v| log::trace!("File: {:?}", v));
 
+        let mut plan = Plan::new();
+
         for file in files {
             let Some(filename) = file.file_name() else {
                 log::warn!("Cannot create folder with empty name for file {:?}", file);
-                continue
+                continue;
             };
 
             let Some(filestem) = file.file_stem() else {
                 log::warn!("Cannot create folder with empty name for file {:?}", file);
-                continue
+                continue;
             };
 
-            let folder = file.parent().unwrap().join(&filestem);
-
-            fs::create_dir(&folder).ok();
-
+            let folder = file.parent().unwrap().join(filestem);
             let new_path = folder.join(filename);
 
-            if let Err(why) = fs::rename(&file, new_path) {
-                log::error!("Cannot move file {:?} into {:?}\n{:#?}", file, folder, why);
-            }
+            plan.mkdir(folder);
+            plan.rename(&file, new_path);
         }
 
-        Ok(())
+        plan.execute_reported()
     }
 }
@@ -1,3 +1 @@
-fn main() {
-    println!("Hello, world!");
-}
+macros::lib_main!(to_mp3);
@@ -1,9 +1,12 @@
 use clap::*;
 use human_bytes::human_bytes;
+use macros::cancel::{self, Cancelled, Partial};
+use macros::plan::Plan;
+use macros::report::Item;
+use macros::watch;
 use serde::*;
-use std::fs;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Stdio};
 use walkdir::WalkDir;
 
 #[derive(Deserialize)]
@@ -65,6 +68,9 @@ pub struct Args {
 
     #[arg(long, short, default_value_t = String::from("jpn"))]
     audio_name: String,
+
+    #[command(flatten)]
+    watch: watch::WatchArgs,
 }
 
 impl Args {
@@ -74,15 +80,37 @@ impl Args {
             .max_depth(self.depth)
             .into_iter()
             .filter_map(Result::ok)
-            .filter(|v| v.path().extension().filter(|x| x == &"mkv").is_some());
+            .filter(|v| v.path().extension().filter(|x| x == &"mkv").is_some())
+            .filter(|v| watch::includes(v.path()));
 
         let mut saved = 0;
+        let mut finished = Vec::new();
+        let mut total = 0;
 
         for entry in iter {
-            let mut info = MkvInfo::from_path(entry.path())?;
+            if cancel::is_cancelled() {
+                break;
+            }
+
+            let item = match self.replace {
+                true => Item::new("retain_audio", entry.path()).output(entry.path()),
+                false => Item::new("retain_audio", entry.path())
+                    .output(Path::new(&self.output_dir).join(entry.file_name())),
+            };
+
+            let mut info = match MkvInfo::from_path(entry.path()) {
+                Ok(v) => v,
+                Err(why) => {
+                    log::error!("Cannot read the tracks of {:?}\n{:#?}", entry.path(), why);
+                    item.failed(why);
+                    continue;
+                }
+            };
+
             info.tracks.retain(|v| v.r#type == "audio");
 
             if info.tracks.len() < 2 {
+                item.skipped("Only a single audio track");
                 continue;
             }
 
@@ -97,6 +125,7 @@ impl Args {
 
             if let Some(track_id) = maybe_track_id {
                 log::info!("Processing {:#?}", entry.path());
+                total += 1;
 
                 match self.retain_audio(entry.path(), track_id) {
                     Ok((original, retained)) => {
@@ -106,14 +135,27 @@ impl Args {
                             human_bytes(retained as f64)
                         );
                         saved += original - retained;
+                        item.succeeded();
+                        finished.push(entry.into_path());
+                    }
+                    Err(_) if cancel::is_cancelled() => item.failed(Cancelled),
+                    Err(why) => {
+                        log::error!("Failed to process a video\n{:#?}", why);
+                        item.failed(why);
                     }
-                    Err(why) => log::error!("Failed to process a video\n{:#?}", why),
                 }
+            } else {
+                item.skipped(format!("No {} audio track", self.audio_name));
             }
         }
 
         log::info!("Saved total {}", human_bytes(saved as f64));
 
+        if cancel::is_cancelled() {
+            cancel::summary(&finished, total);
+            return Err(Cancelled.into());
+        }
+
         Ok(())
     }
 
@@ -124,21 +166,44 @@ impl Args {
         let output = PathBuf::from(&self.output_dir);
         let output_path = output.join(filename);
 
-        Command::new("mkvmerge")
+        if output_path.canonicalize().ok() == Some(path.canonicalize()?) {
+            anyhow::bail!("The output {:?} is the input itself", output_path);
+        }
+
+        if macros::global().dry_run {
+            log::info!("mkvmerge {:?} => {:?}", path, output_path);
+            let size = path.metadata()?.len();
+            return Ok((size, size));
+        }
+
+        let partial = Partial::replacing(output_path);
+        let mut child = Command::new("mkvmerge")
             .arg("-o")
-            .arg(&output_path)
+            .arg(partial.path())
             .arg("-a")
             .arg(track_id.to_string())
             .arg(path)
-            .output()?;
+            .stdout(Stdio::null())
+            .spawn()?;
+
+        let status = cancel::wait(&mut child)?;
+
+        // 1 means the output is complete but mkvmerge has warnings, 2 is an error
+        if !matches!(status.code(), Some(0 | 1)) {
+            anyhow::bail!("mkvmerge exited with {status}");
+        }
+
+        let output_path = partial.finish()?;
 
         let original = path.metadata()?.len();
         let retained = output_path.metadata()?.len();
 
         if self.replace {
             log::info!("Replacing");
-            fs::remove_file(path)?;
-            fs::rename(output_path, path)?;
+            let mut plan = Plan::new();
+            plan.delete(path);
+            plan.rename(output_path, path);
+            plan.execute()?;
         }
 
         Ok((original, retained))
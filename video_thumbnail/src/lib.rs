@@ -1,12 +1,13 @@
 use clap::*;
+use macros::report::Item;
 use std::ffi::OsStr;
 use std::fs;
 use std::io::{Error, ErrorKind, Result};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
-const IMG_EXT: &'static [&'static str] = &["jpg", "jpeg", "png", "tiff", "webp"];
-const THUMBNAIL_NAME: &'static [&'static str] = &["folder", "cover", "thumbnail", "thumb"];
+const IMG_EXT: &[&str] = &["jpg", "jpeg", "png", "tiff", "webp"];
+const THUMBNAIL_NAME: &[&str] = &["folder", "cover", "thumbnail", "thumb"];
 
 #[derive(Parser, Debug)]
 /// Thumbnailning video folders for `nemo` file explorer on linux
@@ -20,13 +21,23 @@ impl Args {
         fs::read_dir(&self.location)?
             .filter_map(Result::ok)
             .filter(|v| v.metadata().map(|p| p.is_dir()).unwrap_or(false))
-            .for_each(|v| match change_thumbnail(&v.path()) {
-                Ok(p) => log::info!(
-                    "Success thumbnailing for {:?}\nThumbnail: {:?}",
-                    v.path(),
-                    p
-                ),
-                Err(why) => log::error!("Cannot change thumbnail for {:?}\n{:#?}", v.path(), why),
+            .for_each(|v| {
+                let item = Item::new("thumbnail", v.path());
+
+                match change_thumbnail(&v.path()) {
+                    Ok(p) => {
+                        log::info!(
+                            "Success thumbnailing for {:?}\nThumbnail: {:?}",
+                            v.path(),
+                            p
+                        );
+                        item.output(p).succeeded();
+                    }
+                    Err(why) => {
+                        log::error!("Cannot change thumbnail for {:?}\n{:#?}", v.path(), why);
+                        item.failed(why);
+                    }
+                }
             });
 
         Ok(())
@@ -34,6 +45,11 @@ impl Args {
 }
 
 fn gio_set_thumbnail(dir: &Path, thumbnail: &Path) -> Result<()> {
+    if macros::global().dry_run {
+        log::info!("gio set {:?} metadata::custom-icon {:?}", dir, thumbnail);
+        return Ok(());
+    }
+
     let mut command = Command::new("gio");
 
     command
@@ -66,9 +82,7 @@ fn is_known_by_name(s: &OsStr) -> bool {
 
 fn change_thumbnail(path: &Path) -> Result<PathBuf> {
     let dir_name = path.file_name().unwrap();
-    let entries = fs::read_dir(&path)?
-        .filter_map(|v| v.ok())
-        .map(|v| v.path());
+    let entries = fs::read_dir(path)?.filter_map(|v| v.ok()).map(|v| v.path());
 
     let mut maybe = Vec::new();
 
@@ -90,10 +104,10 @@ fn change_thumbnail(path: &Path) -> Result<PathBuf> {
             continue;
         };
 
-        if name == dir_name || is_known_by_name(name) || is_getchu_name(&name) {
-            if gio_set_thumbnail(path, &entry).is_ok() {
-                return Ok(entry);
-            }
+        if (name == dir_name || is_known_by_name(name) || is_getchu_name(name))
+            && gio_set_thumbnail(path, &entry).is_ok()
+        {
+            return Ok(entry);
         }
 
         maybe.push((entry.to_path_buf(), name.to_os_string()));
@@ -105,7 +119,7 @@ fn change_thumbnail(path: &Path) -> Result<PathBuf> {
         .or_else(|| maybe.first().map(|(path, _)| path));
 
     if let Some(guessed) = guess {
-        if gio_set_thumbnail(path, &guessed).is_ok() {
+        if gio_set_thumbnail(path, guessed).is_ok() {
             return Ok(guessed.to_path_buf());
         }
     }
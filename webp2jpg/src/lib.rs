@@ -1,10 +1,12 @@
-use anyhow::*;
+use anyhow::{Context as _, Result};
 use clap::*;
+use macros::plan::Plan;
+use macros::report::Item;
+use macros::watch;
 use std::fs;
 use std::io::Read as _;
 use std::io::Seek as _;
-use std::io::Write as _;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Parser)]
 pub struct Args {
@@ -14,7 +16,7 @@ pub struct Args {
     #[arg(long, short, default_value_t = false)]
     /// replace the output.jpg file if exist
     replace: bool,
-    #[arg(long, short, default_value_t = true)]
+    #[arg(long, short = 'D', default_value_t = true)]
     /// Delete original image
     delete: bool,
     #[arg(long, short, default_value_t = 5)]
@@ -23,6 +25,8 @@ pub struct Args {
     #[arg(long, short, default_value_t = false)]
     /// should follow symlink or not
     follow_symlink: bool,
+    #[command(flatten)]
+    watch: watch::WatchArgs,
 }
 
 impl Args {
@@ -33,12 +37,19 @@ impl Args {
             .max_depth(self.depth)
             .into_iter()
             .filter_map(Result::ok)
-            .filter(|v| v.file_type().is_file());
+            .filter(|v| v.file_type().is_file() && watch::includes(v.path()));
 
         for entry in walker {
-            if let Err(why) = process(entry.path(), self) {
-                log::error!("Cannot process {}\n{:#?}", entry.path().display(), why);
-                continue;
+            let item = Item::new("convert", entry.path());
+
+            match process(entry.path(), self) {
+                Ok(Converted::NotWebp) => {}
+                Ok(Converted::Skipped(reason)) => item.skipped(reason),
+                Ok(Converted::Done(output)) => item.output(output).succeeded(),
+                Err(why) => {
+                    log::error!("Cannot process {}\n{:#?}", entry.path().display(), why);
+                    item.failed(why);
+                }
             }
         }
 
@@ -46,13 +57,21 @@ impl Args {
     }
 }
 
-fn process(path: &Path, opt: &Args) -> Result<()> {
+/// What became of a file
+enum Converted {
+    /// Not an image to convert, it is not reported
+    NotWebp,
+    Skipped(&'static str),
+    Done(PathBuf),
+}
+
+fn process(path: &Path, opt: &Args) -> Result<Converted> {
     let mut file = fs::File::open(path)?;
     if !is_webp(&mut file)? {
-        return Ok(());
+        return Ok(Converted::NotWebp);
     }
 
-    println!(""); // skip 1 line
+    println!(); // skip 1 line
     log::info!("Processing {}", path.to_string_lossy());
     let image = image::load(std::io::BufReader::new(file), image::ImageFormat::WebP)?;
     let name = path.file_stem().context("Failed to get filename")?;
@@ -65,27 +84,30 @@ fn process(path: &Path, opt: &Args) -> Result<()> {
         log::warn!("Path exist");
         if !opt.replace {
             log::warn!("Skipping");
-            return Ok(());
+            return Ok(Converted::Skipped("The output already exists"));
         }
     }
 
+    let mut output = std::io::Cursor::new(Vec::new());
+    image.write_to(&mut output, image::ImageFormat::Jpeg)?;
+
+    let mut plan = Plan::new();
+    plan.write(&output_path, output.into_inner());
+
     if opt.delete {
         log::info!("Removing");
-        fs::remove_file(path)?;
+        plan.delete(path);
     }
 
-    let mut output = std::io::BufWriter::new(fs::File::create(output_path)?);
-
-    image.write_to(&mut output, image::ImageFormat::Jpeg)?;
-    output.flush()?;
+    plan.execute()?;
     log::info!("Done");
 
-    Ok(())
+    Ok(Converted::Done(output_path))
 }
 
 fn is_webp(file: &mut fs::File) -> Result<bool> {
     let mut buf: [u8; 12] = Default::default();
     file.read_exact(&mut buf)?;
     file.rewind()?;
-    return Ok(infer::image::is_webp(&buf));
+    Ok(infer::image::is_webp(&buf))
 }